@@ -0,0 +1,56 @@
+// シャード化したキースペースがスレッド数に応じてスケールするかを測るベンチマーク
+//
+//   cargo run --release --example store_bench
+use redis_starter_rust::store;
+use std::thread;
+use std::time::Instant;
+
+const OPS_PER_THREAD: usize = 200_000;
+const KEYS: usize = 10_000;
+
+fn main() {
+    let keys = (0..KEYS)
+        .map(|i| format!("bench:{}", i))
+        .collect::<Vec<_>>();
+    for key in &keys {
+        store::set(0, key, "value", None);
+    }
+
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    println!("shards: {}, cores: {}", store::SHARDS, max_threads);
+    println!("{:>8} {:>14} {:>8}", "threads", "ops/sec", "speedup");
+
+    let mut baseline = None;
+    let mut threads = 1;
+    while threads <= max_threads.max(8) {
+        let ops_per_sec = run(&keys, threads);
+        let baseline = *baseline.get_or_insert(ops_per_sec);
+        println!(
+            "{:>8} {:>14.0} {:>7.2}x",
+            threads,
+            ops_per_sec,
+            ops_per_sec / baseline
+        );
+        threads *= 2;
+    }
+}
+
+// GET:SET = 9:1 の負荷を threads 本のスレッドでかけ、全体の ops/sec を返す
+fn run(keys: &[String], threads: usize) -> f64 {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for t in 0..threads {
+            scope.spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    let key = &keys[(i * 7919 + t * 104729) % keys.len()];
+                    if i % 10 == 0 {
+                        store::set(0, key, "value", None);
+                    } else {
+                        let _ = store::get(0, key);
+                    }
+                }
+            });
+        }
+    });
+    (threads * OPS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
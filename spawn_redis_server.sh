@@ -10,4 +10,5 @@ exec cargo run \
     --release \
     --target-dir=/tmp/codecrafters-redis-target \
     --manifest-path $(dirname $0)/Cargo.toml \
+    --bin redis-starter-rust \
     -- "$@"
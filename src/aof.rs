@@ -0,0 +1,869 @@
+use crate::command::{RedisCommand, RestoreCommandOption, SetCommandOption};
+use crate::data::Data;
+use crate::handler::{self, Client};
+use crate::rdb;
+use crate::replication;
+use crate::resp::RESP;
+use crate::store::{self, SnapshotEntry};
+use lazy_static::lazy_static;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+// AOF は Redis 7 と同じく appenddirname のディレクトリに複数のファイルとして置く。
+// ベースファイル (RDB または AOF 形式) に、その後の書き込みを記録した INCR ファイルが続き、
+// どのファイルをどの順に読むかはマニフェストに書く
+const MANIFEST_SUFFIX: &str = ".manifest";
+// 自動の書き換えが失敗したら、次の試行までこれだけ待つ (ms)
+const REWRITE_RETRY_DELAY: u128 = 5000;
+
+lazy_static! {
+    static ref AOF: Mutex<Aof> = Mutex::new(Aof {
+        config: AofConfig::default(),
+        dir: PathBuf::new(),
+        filename: String::new(),
+        manifest: Manifest::default(),
+        file: None,
+        selected_db: None,
+        current_size: 0,
+        base_size: 0,
+        last_write_ok: true,
+        rewrite_in_progress: false,
+        last_rewrite_ok: true,
+        last_rewrite_try: 0,
+        base_seq: 0,
+        restarting: false,
+    });
+}
+
+struct Aof {
+    config: AofConfig,
+    // appenddirname のディレクトリと、各ファイル名の元になる appendfilename
+    dir: PathBuf,
+    filename: String,
+    manifest: Manifest,
+    // 書き込み中の INCR ファイル。appendonly が no なら None
+    file: Option<File>,
+    // 最後に書き込んだ SELECT の DB。INCR ファイルを開いた直後は None
+    selected_db: Option<usize>,
+    // ベースと INCR ファイルの合計サイズと、最後に書き換えたときのベースのサイズ
+    current_size: u64,
+    base_size: u64,
+    last_write_ok: bool,
+    rewrite_in_progress: bool,
+    last_rewrite_ok: bool,
+    // 最後に書き換えを始めた時刻 (ms)
+    last_rewrite_try: u128,
+    // 最後に書き換えを始めたベースの番号。後から別の書き換えが始まっていれば、前の書き換えの結果は捨てる
+    base_seq: u64,
+    // 全体の同期の後で作り直している間は、新しいベースができるまで古いファイルだけをマニフェストに残す
+    restarting: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AofConfig {
+    pub fsync: AppendFsync,
+    // 最後のファイルの末尾が途中で切れていたら、切り捨てて読み込みを続ける
+    pub load_truncated: bool,
+    // 書き換えたベースを RDB 形式にする
+    pub use_rdb_preamble: bool,
+    // ベースから percentage % 以上大きくなり、min_size 以上になったら自動で書き換える。0 なら書き換えない
+    pub auto_rewrite_percentage: u64,
+    pub auto_rewrite_min_size: u64,
+}
+
+impl Default for AofConfig {
+    fn default() -> Self {
+        AofConfig {
+            fsync: AppendFsync::Everysec,
+            load_truncated: true,
+            use_rdb_preamble: true,
+            auto_rewrite_percentage: 100,
+            auto_rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AofError {
+    #[error("ERR Background append only file rewriting already in progress")]
+    RewriteInProgress,
+    #[error("ERR Append only file is not enabled")]
+    Disabled,
+    #[error("ERR {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AppendFsync {
+    // 書き込みのたびに fsync する
+    Always,
+    // 1秒ごとにバックグラウンドで fsync する
+    Everysec,
+    // fsync は OS に任せる
+    No,
+}
+
+impl AppendFsync {
+    pub fn new(policy: &str) -> Self {
+        match policy {
+            "always" => AppendFsync::Always,
+            "everysec" => AppendFsync::Everysec,
+            "no" => AppendFsync::No,
+            _ => panic!("unknown appendfsync policy"),
+        }
+    }
+}
+
+pub fn configure(dir: &Path, filename: &str, config: AofConfig) {
+    let mut aof = AOF.lock().unwrap();
+    aof.dir = dir.to_path_buf();
+    aof.filename = filename.to_string();
+    aof.config = config;
+}
+
+pub fn enabled() -> bool {
+    AOF.lock().unwrap().file.is_some()
+}
+
+// 起動時にマニフェストに従ってベースと INCR ファイルを順に読み込む。
+// AOF がなければ何もせず false を返す。
+// 1つのファイルだった頃の AOF (dir/appendfilename) があれば、ベースとしてディレクトリに移す
+pub fn load() -> io::Result<bool> {
+    let (dir, filename, load_truncated) = {
+        let aof = AOF.lock().unwrap();
+        (
+            aof.dir.clone(),
+            aof.filename.clone(),
+            aof.config.load_truncated,
+        )
+    };
+    let Some(manifest) = read_manifest(&dir, &filename)? else {
+        return Ok(false);
+    };
+    let files = manifest
+        .base
+        .iter()
+        .chain(&manifest.incr)
+        .collect::<Vec<_>>();
+    for (i, file) in files.iter().enumerate() {
+        let path = dir.join(&file.name);
+        // 末尾の切り捨てを許すのは、最後に書き込んでいたファイルだけ
+        let loaded = if file.name.ends_with(".rdb") {
+            rdb::load_file(&path)?
+        } else {
+            load_file(&path, load_truncated && i == files.len() - 1)?
+        };
+        if !loaded {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} listed in the manifest does not exist", path.display()),
+            ));
+        }
+    }
+    AOF.lock().unwrap().manifest = manifest;
+    Ok(true)
+}
+
+// 起動時に AOF を再生してストアに反映する。ファイルがなければ何もせず false を返す。
+// 先頭が RDB (RDB プリアンブル) なら、それを読み込んでから続くコマンドを再生する
+pub fn load_file(path: &Path, load_truncated: bool) -> io::Result<bool> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut pos = 0;
+    if data.starts_with(b"REDIS") {
+        let (_, len) =
+            rdb::load_prefix(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        pos = len;
+    }
+    let mut client = Client::default();
+    while pos < data.len() {
+        if data[pos] != b'*' {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad file format at offset {}", pos),
+            ));
+        }
+        let Some((resp, len)) = RESP::parse(&data[pos..]) else {
+            // 書き込み途中で落ちると、最後のコマンドが途中までしか残らない
+            if !load_truncated {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("unexpected end of file at offset {}", pos),
+                ));
+            }
+            println!(
+                "AOF {} is truncated at offset {}, discarding the incomplete command",
+                path.display(),
+                pos
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(pos as u64)?;
+            break;
+        };
+        handler::handle_redis_command(RedisCommand::new(resp), &mut client);
+        pos += len;
+    }
+    Ok(true)
+}
+
+// 以降の書き込みを最後の INCR ファイルに追記する。ベースがなければ、それまでのデータが
+// 失われないよう現在のストアの内容からベースを作る
+pub fn start() -> io::Result<()> {
+    let mut aof = AOF.lock().unwrap();
+    fs::create_dir_all(&aof.dir)?;
+    if aof.manifest.base.is_none() {
+        let base = write_base(&aof, 1, &store::snapshot().entries())?;
+        aof.manifest.base = Some(base);
+    }
+    if aof.manifest.incr.is_empty() {
+        let incr = AofFile::incr(&aof.filename, 1);
+        aof.manifest.incr.push(incr);
+    }
+    let file = open_incr(&aof)?;
+    write_manifest(&aof)?;
+    aof.file = Some(file);
+    aof.selected_db = None;
+    aof.base_seq = aof.manifest.base.as_ref().unwrap().seq;
+    aof.base_size = file_size(&aof, aof.manifest.base.as_ref().unwrap())?;
+    aof.current_size = aof.base_size + incr_size(&aof)?;
+    if aof.config.fsync == AppendFsync::Everysec {
+        thread::spawn(fsync_every_second);
+    }
+    Ok(())
+}
+
+// BGREWRITEAOF: 書き込みを新しい INCR ファイルに切り替え、切り替えた時点のデータから
+// 新しいベースをバックグラウンドで作る。できたら、それより前のファイルは不要になる
+pub fn rewrite() -> Result<(), AofError> {
+    start_rewrite(false)
+}
+
+// レプリカが全体の同期でデータを置き換えた後に呼ぶ。それまでの履歴は新しいデータとつながらないので、
+// Redis と同じく読み込んだデータからベースを作り直して古いファイルを捨てる。
+// 書き換え中のものは同期の前のデータから作っているので、終わるのを待たずに捨てる
+pub fn restart_after_sync() -> Result<(), AofError> {
+    match start_rewrite(true) {
+        Err(AofError::Disabled) => Ok(()),
+        ret => ret,
+    }
+}
+
+fn start_rewrite(restart: bool) -> Result<(), AofError> {
+    // ファイルを開いてマニフェストを書く間は書き込みを止めないよう、先に新しい INCR ファイルを用意する
+    let (dir, filename, incr, manifest, base_seq) = {
+        let mut aof = AOF.lock().unwrap();
+        if aof.file.is_none() {
+            return Err(AofError::Disabled);
+        }
+        if aof.rewrite_in_progress && !restart {
+            return Err(AofError::RewriteInProgress);
+        }
+        aof.restarting |= restart;
+        // 書き換え中のものがあれば、番号を進めて結果を捨てさせる
+        aof.base_seq += 1;
+        aof.rewrite_in_progress = true;
+        aof.last_rewrite_try = store::now();
+        let seq = aof.manifest.incr.last().map_or(1, |incr| incr.seq + 1);
+        let incr = AofFile::incr(&aof.filename, seq);
+        aof.manifest.incr.push(incr.clone());
+        let manifest = (!aof.restarting).then(|| aof.manifest.to_string());
+        (
+            aof.dir.clone(),
+            aof.filename.clone(),
+            incr,
+            manifest,
+            aof.base_seq,
+        )
+    };
+    // 切り替えたことをマニフェストに書いてから新しいファイルに書き込む
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(&incr.name))
+        .and_then(|file| {
+            if let Some(manifest) = &manifest {
+                rdb::write_file(&manifest_path(&dir, &filename), manifest.as_bytes())?;
+            }
+            Ok(file)
+        });
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            let mut aof = AOF.lock().unwrap();
+            if aof.base_seq == base_seq {
+                aof.manifest.incr.retain(|file| file.seq != incr.seq);
+                aof.rewrite_in_progress = false;
+                aof.last_rewrite_ok = false;
+            }
+            return Err(e.into());
+        }
+    };
+    let (snapshot, prev) = {
+        // 書き込みを止めて、スナップショットと INCR ファイルの切り替えを同じ時点で行う。
+        // スナップショットはデータを共有するだけで、複製はバックグラウンドで行うので、止めるのは一瞬で済む
+        let _guard = replication::write_lock();
+        let mut aof = AOF.lock().unwrap();
+        // その間に全体の同期の後の作り直しが始まっていれば、そちらに任せる。
+        // この INCR ファイルはそちらの書き換えが終わると捨てられる
+        if aof.base_seq != base_seq {
+            return Ok(());
+        }
+        let prev = aof.file.replace(file);
+        aof.selected_db = None;
+        (store::snapshot(), prev)
+    };
+    // 前の INCR ファイルには以降書き込まないので、ここで書き込みを確定させる
+    if let Some(Err(e)) = prev.map(|file| file.sync_data()) {
+        println!("error syncing the AOF: {}", e);
+    }
+    let use_rdb_preamble = AOF.lock().unwrap().config.use_rdb_preamble;
+    thread::spawn(move || {
+        let ret = encode_base(&snapshot.entries(), use_rdb_preamble).and_then(|(data, kind)| {
+            let base = AofFile::base(&filename, base_seq, kind);
+            rdb::write_file(&dir.join(&base.name), &data).map(|()| base)
+        });
+        let mut aof = AOF.lock().unwrap();
+        if aof.base_seq != base_seq {
+            if let Ok(base) = ret {
+                let _ = fs::remove_file(dir.join(&base.name));
+            }
+            return;
+        }
+        aof.rewrite_in_progress = false;
+        let ret = ret.and_then(|base| finish_rewrite(&mut aof, base, incr.seq));
+        if let Err(e) = &ret {
+            println!("background AOF rewrite error: {}", e);
+        }
+        aof.last_rewrite_ok = ret.is_ok();
+    });
+    Ok(())
+}
+
+// 新しいベースと、切り替え後の INCR ファイルだけをマニフェストに残し、古いファイルを消す
+fn finish_rewrite(aof: &mut Aof, base: AofFile, first_incr_seq: u64) -> io::Result<()> {
+    let mut manifest = aof.manifest.clone();
+    let old_base = manifest.base.replace(base);
+    let (incr, old_incr) = manifest
+        .incr
+        .into_iter()
+        .partition(|incr| incr.seq >= first_incr_seq);
+    manifest.incr = incr;
+    let old = std::mem::replace(&mut aof.manifest, manifest);
+    if let Err(e) = write_manifest(aof) {
+        aof.manifest = old;
+        return Err(e);
+    }
+    aof.restarting = false;
+    for file in old_base.into_iter().chain(old_incr) {
+        if let Err(e) = fs::remove_file(aof.dir.join(&file.name)) {
+            println!("failed to remove {}: {}", file.name, e);
+        }
+    }
+    aof.base_size = file_size(aof, aof.manifest.base.as_ref().unwrap())?;
+    aof.current_size = aof.base_size + incr_size(aof)?;
+    Ok(())
+}
+
+// 自動書き換えの条件を満たしていれば BGREWRITEAOF する。persistence の cron から呼ぶ
+pub fn rewrite_if_needed() {
+    let needed = {
+        let aof = AOF.lock().unwrap();
+        // 失敗した直後は、少し間を空けてから再試行する
+        let can_retry =
+            aof.last_rewrite_ok || store::now() - aof.last_rewrite_try > REWRITE_RETRY_DELAY;
+        // 全体の同期の後の作り直しが失敗していれば、大きさによらず作り直す
+        aof.file.is_some()
+            && !aof.rewrite_in_progress
+            && can_retry
+            && (aof.restarting || needs_rewrite(aof.current_size, aof.base_size, &aof.config))
+    };
+    if needed {
+        println!("starting automatic rewriting of AOF");
+        let _ = rewrite();
+    }
+}
+
+fn needs_rewrite(current_size: u64, base_size: u64, config: &AofConfig) -> bool {
+    if config.auto_rewrite_percentage == 0 || current_size < config.auto_rewrite_min_size {
+        return false;
+    }
+    let growth = current_size.saturating_sub(base_size) * 100 / base_size.max(1);
+    growth >= config.auto_rewrite_percentage
+}
+
+// ベースの中身と種類を返す。AOF 形式ではコマンドで表せる文字列しか書けないので、
+// それ以外の値があれば RDB 形式にする
+fn encode_base(
+    snapshot: &[(usize, Vec<SnapshotEntry>)],
+    use_rdb_preamble: bool,
+) -> io::Result<(Vec<u8>, BaseKind)> {
+    if !use_rdb_preamble {
+        if let Some(data) = encode_commands(snapshot) {
+            return Ok((data, BaseKind::Aof));
+        }
+        println!("the dataset has non-string values, writing the AOF base in RDB format");
+    }
+    Ok((rdb::encode(&[], snapshot), BaseKind::Rdb))
+}
+
+fn encode_commands(snapshot: &[(usize, Vec<SnapshotEntry>)]) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    for (db, entries) in snapshot {
+        data.extend(RedisCommand::Select { index: *db }.to_resp().as_bytes());
+        for (key, value, expires_at) in entries {
+            let Data::String(value) = &**value else {
+                return None;
+            };
+            let command = RedisCommand::Set {
+                key: key.clone(),
+                value: value.clone(),
+                options: expires_at.map(SetCommandOption::Pxat).into_iter().collect(),
+            };
+            data.extend(command.to_resp().as_bytes());
+        }
+    }
+    Some(data)
+}
+
+fn write_base(
+    aof: &Aof,
+    seq: u64,
+    snapshot: &[(usize, Vec<SnapshotEntry>)],
+) -> io::Result<AofFile> {
+    let (data, kind) = encode_base(snapshot, aof.config.use_rdb_preamble)?;
+    let base = AofFile::base(&aof.filename, seq, kind);
+    rdb::write_file(&aof.dir.join(&base.name), &data)?;
+    Ok(base)
+}
+
+fn open_incr(aof: &Aof) -> io::Result<File> {
+    let incr = aof.manifest.incr.last().unwrap();
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(aof.dir.join(&incr.name))
+}
+
+fn file_size(aof: &Aof, file: &AofFile) -> io::Result<u64> {
+    Ok(fs::metadata(aof.dir.join(&file.name))?.len())
+}
+
+fn incr_size(aof: &Aof) -> io::Result<u64> {
+    aof.manifest
+        .incr
+        .iter()
+        .map(|incr| file_size(aof, incr))
+        .sum()
+}
+
+fn manifest_path(dir: &Path, filename: &str) -> PathBuf {
+    dir.join(format!("{}{}", filename, MANIFEST_SUFFIX))
+}
+
+fn write_manifest(aof: &Aof) -> io::Result<()> {
+    rdb::write_file(
+        &manifest_path(&aof.dir, &aof.filename),
+        aof.manifest.to_string().as_bytes(),
+    )
+}
+
+// マニフェストを読む。なければ、1つのファイルだった頃の AOF をベースとするマニフェストを作る
+fn read_manifest(dir: &Path, filename: &str) -> io::Result<Option<Manifest>> {
+    let path = manifest_path(dir, filename);
+    match fs::read_to_string(&path) {
+        Ok(s) => {
+            let manifest = Manifest::parse(&s).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid manifest {}: {}", path.display(), e),
+                )
+            })?;
+            return Ok(Some(manifest));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let legacy = dir.parent().unwrap_or(Path::new(".")).join(filename);
+    if !legacy.exists() {
+        return Ok(None);
+    }
+    println!("upgrading {} to a multi part AOF", legacy.display());
+    fs::create_dir_all(dir)?;
+    fs::rename(&legacy, dir.join(filename))?;
+    let manifest = Manifest {
+        base: Some(AofFile {
+            name: filename.to_string(),
+            seq: 1,
+            kind: FileKind::Base,
+        }),
+        incr: vec![],
+    };
+    rdb::write_file(&path, manifest.to_string().as_bytes())?;
+    Ok(Some(manifest))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BaseKind {
+    Rdb,
+    Aof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileKind {
+    Base,
+    // 書き換えで不要になり、消される予定のファイル
+    History,
+    Incr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    kind: FileKind,
+}
+
+impl AofFile {
+    fn base(filename: &str, seq: u64, kind: BaseKind) -> Self {
+        let ext = match kind {
+            BaseKind::Rdb => "rdb",
+            BaseKind::Aof => "aof",
+        };
+        AofFile {
+            name: format!("{}.{}.base.{}", filename, seq, ext),
+            seq,
+            kind: FileKind::Base,
+        }
+    }
+
+    fn incr(filename: &str, seq: u64) -> Self {
+        AofFile {
+            name: format!("{}.{}.incr.aof", filename, seq),
+            seq,
+            kind: FileKind::Incr,
+        }
+    }
+}
+
+// 1行に1ファイルずつ "file <name> seq <seq> type <b|h|i>" と書く。
+// history のファイルは読み込みには使わないので、読むときに捨てる
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Manifest {
+    base: Option<AofFile>,
+    incr: Vec<AofFile>,
+}
+
+impl Manifest {
+    fn parse(s: &str) -> Result<Self, String> {
+        let mut manifest = Manifest::default();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if !words.len().is_multiple_of(2) {
+                return Err(format!("invalid line: {}", line));
+            }
+            let field = |key: &str| {
+                words
+                    .chunks(2)
+                    .find(|pair| pair[0] == key)
+                    .map(|pair| pair[1])
+                    .ok_or_else(|| format!("missing {}: {}", key, line))
+            };
+            let name = field("file")?.to_string();
+            let seq = field("seq")?
+                .parse()
+                .map_err(|_| format!("invalid seq: {}", line))?;
+            let kind = match field("type")? {
+                "b" => FileKind::Base,
+                "h" => FileKind::History,
+                "i" => FileKind::Incr,
+                _ => return Err(format!("invalid type: {}", line)),
+            };
+            let file = AofFile { name, seq, kind };
+            match kind {
+                FileKind::Base if manifest.base.is_some() => {
+                    return Err("found duplicate base file".to_string())
+                }
+                FileKind::Base => manifest.base = Some(file),
+                FileKind::History => {}
+                FileKind::Incr => manifest.incr.push(file),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in self.base.iter().chain(&self.incr) {
+            let kind = match file.kind {
+                FileKind::Base => "b",
+                FileKind::History => "h",
+                FileKind::Incr => "i",
+            };
+            writeln!(f, "file {} seq {} type {}", file.name, file.seq, kind)?;
+        }
+        Ok(())
+    }
+}
+
+// 書き込みコマンドを実行した順に追記する。replication::write_lock の中で呼ばれる
+pub fn feed(db: usize, command: RedisCommand) {
+    let mut aof = AOF.lock().unwrap();
+    if aof.file.is_none() {
+        return;
+    }
+    let mut data = vec![];
+    if aof.selected_db != Some(db) {
+        data.extend(RedisCommand::Select { index: db }.to_resp().as_bytes());
+    }
+    data.extend(absolute_expire(command).to_resp().as_bytes());
+    let fsync = aof.config.fsync;
+    let file = aof.file.as_mut().unwrap();
+    let ret = file.write_all(&data).and_then(|()| {
+        if fsync == AppendFsync::Always {
+            file.sync_data()
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(e) = &ret {
+        println!("error writing to the AOF: {}", e);
+    }
+    aof.last_write_ok = ret.is_ok();
+    if ret.is_ok() {
+        aof.selected_db = Some(db);
+        aof.current_size += data.len() as u64;
+    }
+}
+
+// 再生するときには時間が経っているので、相対的な有効期限は UNIX 時刻に直して書く
+fn absolute_expire(command: RedisCommand) -> RedisCommand {
+    match command {
+        RedisCommand::Set {
+            key,
+            value,
+            options,
+        } => RedisCommand::Set {
+            key,
+            value,
+            options: options
+                .into_iter()
+                .map(|option| match option {
+                    SetCommandOption::Px(px) => SetCommandOption::Pxat(store::now() + px),
+                    option => option,
+                })
+                .collect(),
+        },
+        RedisCommand::Restore {
+            key,
+            ttl,
+            payload,
+            mut options,
+        } if ttl > 0 && !options.contains(&RestoreCommandOption::Absttl) => {
+            options.push(RestoreCommandOption::Absttl);
+            RedisCommand::Restore {
+                key,
+                ttl: (store::now() + ttl as u128) as i64,
+                payload,
+                options,
+            }
+        }
+        command => command,
+    }
+}
+
+// fsync の間は AOF をロックしないよう、複製したファイルハンドルで行う
+fn fsync_every_second() {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let file = match &AOF.lock().unwrap().file {
+            Some(file) => file.try_clone(),
+            None => return,
+        };
+        if let Err(e) = file.and_then(|file| file.sync_data()) {
+            println!("error syncing the AOF: {}", e);
+        }
+    }
+}
+
+// INFO persistence の AOF の項目
+pub fn info() -> String {
+    let aof = AOF.lock().unwrap();
+    let status = |ok| if ok { "ok" } else { "err" };
+    let mut ret = format!(
+        "aof_enabled:{}\naof_rewrite_in_progress:{}\naof_last_bgrewrite_status:{}\naof_last_write_status:{}",
+        aof.file.is_some() as u8,
+        aof.rewrite_in_progress as u8,
+        status(aof.last_rewrite_ok),
+        status(aof.last_write_ok)
+    );
+    if aof.file.is_some() {
+        ret += &format!(
+            "\naof_current_size:{}\naof_base_size:{}",
+            aof.current_size, aof.base_size
+        );
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_absolute_expire() {
+        let command = RedisCommand::Set {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            options: vec![SetCommandOption::Px(100000)],
+        };
+        let now = store::now();
+        let RedisCommand::Set { options, .. } = absolute_expire(command) else {
+            panic!("not a SET command");
+        };
+        let [SetCommandOption::Pxat(pxat)] = options[..] else {
+            panic!("unexpected options: {:?}", options);
+        };
+        assert!((now + 100000..now + 101000).contains(&pxat));
+    }
+
+    #[test]
+    fn test_manifest() {
+        let s = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                 file appendonly.aof.1.base.rdb seq 1 type h\n\
+                 file appendonly.aof.3.incr.aof seq 3 type i\n\
+                 file appendonly.aof.4.incr.aof seq 4 type i\n";
+        let manifest = Manifest::parse(s).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                base: Some(AofFile::base("appendonly.aof", 2, BaseKind::Rdb)),
+                incr: vec![
+                    AofFile::incr("appendonly.aof", 3),
+                    AofFile::incr("appendonly.aof", 4)
+                ],
+            }
+        );
+        // history は書き出さない
+        assert_eq!(
+            manifest.to_string(),
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.3.incr.aof seq 3 type i\n\
+             file appendonly.aof.4.incr.aof seq 4 type i\n"
+        );
+        assert!(Manifest::parse("file a seq 1 type x\n").is_err());
+        assert!(Manifest::parse("file a seq 1\n").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b\n").is_err());
+    }
+
+    #[test]
+    fn test_needs_rewrite() {
+        let config = AofConfig {
+            auto_rewrite_percentage: 100,
+            auto_rewrite_min_size: 1000,
+            ..AofConfig::default()
+        };
+        assert!(!needs_rewrite(999, 0, &config));
+        assert!(needs_rewrite(1000, 0, &config));
+        assert!(!needs_rewrite(1999, 1000, &config));
+        assert!(needs_rewrite(2000, 1000, &config));
+        let disabled = AofConfig {
+            auto_rewrite_percentage: 0,
+            ..config
+        };
+        assert!(!needs_rewrite(2000, 1000, &disabled));
+    }
+
+    #[test]
+    fn test_encode_base() {
+        let snapshot = vec![(
+            1,
+            vec![
+                (
+                    "k1".to_string(),
+                    Arc::new(Data::String("v1".to_string())),
+                    None,
+                ),
+                (
+                    "k2".to_string(),
+                    Arc::new(Data::String("v2".to_string())),
+                    Some(1000),
+                ),
+            ],
+        )];
+        let (data, kind) = encode_base(&snapshot, false).unwrap();
+        assert_eq!(kind, BaseKind::Aof);
+        assert_eq!(
+            data,
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n\
+              *3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$2\r\nv1\r\n\
+              *5\r\n$3\r\nSET\r\n$2\r\nk2\r\n$2\r\nv2\r\n$4\r\npxat\r\n$4\r\n1000\r\n"
+        );
+        let (data, kind) = encode_base(&snapshot, true).unwrap();
+        assert_eq!(kind, BaseKind::Rdb);
+        assert!(data.starts_with(b"REDIS"));
+        // 文字列以外の値は AOF 形式で書けないので RDB 形式になる
+        let snapshot = vec![(
+            0,
+            vec![(
+                "l".to_string(),
+                Arc::new(Data::List(["a".to_string()].into_iter().collect())),
+                None,
+            )],
+        )];
+        assert_eq!(encode_base(&snapshot, false).unwrap().1, BaseKind::Rdb);
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let mut data = vec![];
+        for command in [
+            RedisCommand::Select { index: 5 },
+            RedisCommand::Set {
+                key: "aof1".to_string(),
+                value: "v1".to_string(),
+                options: vec![],
+            },
+        ] {
+            data.extend(command.to_resp().as_bytes());
+        }
+        let complete = data.len() as u64;
+        // 最後のコマンドが途中で切れている
+        data.extend(b"*3\r\n$3\r\nSET\r\n$4\r\naof2");
+        fs::write(&path, &data).unwrap();
+
+        assert_eq!(
+            load_file(&path, false).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(load_file(&path, true).unwrap());
+        assert_eq!(store::get(5, "aof1"), Ok(Some("v1".to_string())));
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        fs::write(&path, b"+OK\r\n").unwrap();
+        assert_eq!(
+            load_file(&path, true).unwrap_err().to_string(),
+            "bad file format at offset 0"
+        );
+        assert!(!load_file(&dir.join("missing.aof"), true).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+// RDB ファイルを読み込まずに検査する。構造とチェックサムを確かめ、キーごとの概要か JSON を出力する。
+// 壊れていれば、エラーの位置 (バイトオフセット) を表示して 1 で終了する
+//
+//   cargo run --bin redis-check-rdb -- dump.rdb [--json]
+use redis_starter_rust::data::{self, Data};
+use redis_starter_rust::rdb::{self, Entry, EntryLayout, Rdb};
+use redis_starter_rust::store;
+use std::fs;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: redis-check-rdb <file.rdb> [--json]";
+
+fn main() -> ExitCode {
+    let mut path = None;
+    let mut json = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("cannot open {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let rdb = match check(&path, &data) {
+        Ok(rdb) => rdb,
+        Err(report) => {
+            eprint!("{}", report);
+            return ExitCode::FAILURE;
+        }
+    };
+    if json {
+        println!("{}", json_rdb(&rdb));
+    } else {
+        print!("{}", summary(&rdb, store::now()));
+    }
+    ExitCode::SUCCESS
+}
+
+// 壊れていれば、エラーの位置を含む報告を返す
+fn check(path: &str, data: &[u8]) -> Result<Rdb, String> {
+    rdb::parse(data).map_err(|e| {
+        format!(
+            "--- RDB ERROR DETECTED ---\n{}: {} (file size {})\n",
+            path,
+            e,
+            data.len()
+        )
+    })
+}
+
+fn summary(rdb: &Rdb, now: u128) -> String {
+    let mut ret = format!("RDB version {}\n", rdb.version);
+    for (key, value) in &rdb.aux {
+        ret += &format!("aux {} = {:?}\n", key, value);
+    }
+    for (entry, layout) in rdb.entries.iter().zip(&rdb.layouts) {
+        // 残り時間 (ms)。有効期限がなければ -1
+        let ttl = match entry.expires_at {
+            None => "-1".to_string(),
+            Some(expires_at) if expires_at < now => "expired".to_string(),
+            Some(expires_at) => (expires_at - now).to_string(),
+        };
+        ret += &format!(
+            "db={} key={:?} type={} encoding={} ttl={} size={} offset={}\n",
+            entry.db,
+            entry.key,
+            entry.value.type_name(),
+            rdb::encoding_name(layout.value_type),
+            ttl,
+            layout.len,
+            layout.offset
+        );
+    }
+    let expires = rdb
+        .entries
+        .iter()
+        .filter(|e| e.expires_at.is_some())
+        .count();
+    ret += &format!("keys={} expires={}\n", rdb.entries.len(), expires);
+    ret += "RDB looks OK\n";
+    ret
+}
+
+fn json_rdb(rdb: &Rdb) -> String {
+    let aux = json_object(rdb.aux.iter().map(|(k, v)| (k.as_str(), json_string(v))));
+    let entries = rdb
+        .entries
+        .iter()
+        .zip(&rdb.layouts)
+        .map(|(entry, layout)| json_entry(entry, layout))
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"version\":{},\"aux\":{},\"entries\":[{}]}}",
+        rdb.version,
+        aux,
+        entries.join(",")
+    )
+}
+
+fn json_entry(entry: &Entry, layout: &EntryLayout) -> String {
+    let expires_at = entry
+        .expires_at
+        .map_or("null".to_string(), |expires_at| expires_at.to_string());
+    json_object(
+        [
+            ("db", entry.db.to_string()),
+            ("key", json_string(&entry.key)),
+            ("type", json_string(entry.value.type_name())),
+            (
+                "encoding",
+                json_string(rdb::encoding_name(layout.value_type)),
+            ),
+            ("expires_at", expires_at),
+            ("offset", layout.offset.to_string()),
+            ("size", layout.len.to_string()),
+            ("value", json_value(&entry.value)),
+        ]
+        .into_iter(),
+    )
+}
+
+// 集合やハッシュは、出力が毎回同じになるよう並べ替える
+fn json_value(value: &Data) -> String {
+    let strings = |items: Vec<&String>| {
+        let items = items
+            .into_iter()
+            .map(|s| json_string(s))
+            .collect::<Vec<_>>();
+        format!("[{}]", items.join(","))
+    };
+    match value {
+        Data::String(s) => json_string(s),
+        Data::List(list) => strings(list.iter().collect()),
+        Data::Set(set) => {
+            let mut members = set.iter().collect::<Vec<_>>();
+            members.sort();
+            strings(members)
+        }
+        Data::ZSet(zset) => {
+            let members = data::zset_sorted(zset)
+                .into_iter()
+                .map(|(member, score)| format!("[{},{}]", json_string(member), json_number(score)))
+                .collect::<Vec<_>>();
+            format!("[{}]", members.join(","))
+        }
+        Data::Hash(hash) => {
+            let mut fields = hash.iter().collect::<Vec<_>>();
+            fields.sort();
+            json_object(
+                fields
+                    .into_iter()
+                    .map(|(k, v)| (k.as_str(), json_string(v))),
+            )
+        }
+        Data::Stream(stream) => {
+            let entries = stream
+                .entries
+                .iter()
+                .map(|(id, fields)| {
+                    let fields =
+                        json_object(fields.iter().map(|(k, v)| (k.as_str(), json_string(v))));
+                    format!("{{\"id\":\"{}\",\"fields\":{}}}", id, fields)
+                })
+                .collect::<Vec<_>>();
+            let groups = stream
+                .groups
+                .iter()
+                .map(|group| {
+                    let consumers = group.consumers.iter().map(|c| &c.name).collect();
+                    json_object(
+                        [
+                            ("name", json_string(&group.name)),
+                            ("last_id", json_string(&group.last_id.to_string())),
+                            ("pending", group.pending.len().to_string()),
+                            ("consumers", strings(consumers)),
+                        ]
+                        .into_iter(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            json_object(
+                [
+                    ("last_id", json_string(&stream.last_id.to_string())),
+                    ("entries", format!("[{}]", entries.join(","))),
+                    ("groups", format!("[{}]", groups.join(","))),
+                ]
+                .into_iter(),
+            )
+        }
+    }
+}
+
+fn json_object<'a>(fields: impl Iterator<Item = (&'a str, String)>) -> String {
+    let fields = fields
+        .map(|(key, value)| format!("{}:{}", json_string(key), value))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+// 文字列はバイト列を latin-1 として持っているので、各バイトは U+0000 から U+00FF の1文字になる
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret += "\\\"",
+            '\\' => ret += "\\\\",
+            '\n' => ret += "\\n",
+            '\r' => ret += "\\r",
+            '\t' => ret += "\\t",
+            ' '..='~' => ret.push(c),
+            _ => ret += &format!("\\u{:04x}", c as u32),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+// JSON は inf や nan を表せないので文字列にする
+fn json_number(n: f64) -> String {
+    if n.is_finite() {
+        n.to_string()
+    } else {
+        json_string(&n.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\n""#);
+        assert_eq!(json_string("\u{0}\u{ff}"), r#""\u0000\u00ff""#);
+    }
+
+    #[test]
+    fn test_json_rdb() {
+        let snapshot = vec![(
+            0,
+            vec![
+                (
+                    "s".to_string(),
+                    Arc::new(Data::String("v".to_string())),
+                    Some(1000),
+                ),
+                (
+                    "z".to_string(),
+                    Arc::new(Data::ZSet(
+                        [("a".to_string(), f64::INFINITY), ("b".to_string(), 1.5)]
+                            .into_iter()
+                            .collect(),
+                    )),
+                    None,
+                ),
+            ],
+        )];
+        let rdb = rdb::parse(&rdb::encode(&[], &snapshot)).unwrap();
+        let json = json_rdb(&rdb);
+        assert!(json.starts_with(
+            r#"{"version":11,"aux":{"redis-ver":"7.2.0","redis-bits":"64"},"entries":["#
+        ));
+        assert!(json
+            .contains(r#"{"db":0,"key":"s","type":"string","encoding":"raw","expires_at":1000,"#));
+        assert!(json.contains(r#""value":[["b",1.5],["a","inf"]]}"#));
+
+        let summary = summary(&rdb, 500);
+        assert!(summary.contains("db=0 key=\"s\" type=string encoding=raw ttl=500 size="));
+        assert!(summary.contains("db=0 key=\"z\" type=zset encoding=skiplist ttl=-1 size="));
+        assert!(summary.ends_with("keys=2 expires=1\nRDB looks OK\n"));
+    }
+
+    #[test]
+    fn test_check_error_offset() {
+        // 文字列の長さが 2^64 - 1 で、読もうとした位置でファイルが終わっている
+        let data = b"REDIS0011\x00\x81\xff\xff\xff\xff\xff\xff\xff\xff";
+        assert_eq!(
+            check("overflow.rdb", data).unwrap_err(),
+            "--- RDB ERROR DETECTED ---\n\
+             overflow.rdb: unexpected end of file at offset 19 (file size 19)\n"
+        );
+        // LZF の展開後の長さが 2^46 で、展開できない
+        let data = b"REDIS0011\x00\xc3\x02\x81\x00\x00\x40\x00\x00\x00\x00\x00\x00a";
+        assert_eq!(
+            check("lzf.rdb", data).unwrap_err(),
+            "--- RDB ERROR DETECTED ---\n\
+             lzf.rdb: invalid LZF compressed string at offset 21 (file size 23)\n"
+        );
+    }
+}
@@ -1,9 +1,34 @@
+use crate::aof::AppendFsync;
+use crate::persistence::SaveParam;
 use crate::server_state::Role;
+use crate::store::MaxmemoryPolicy;
 
 pub struct CliArgs {
     pub host: Option<String>,
     pub port: Option<String>,
     pub role: Role,
+    pub databases: Option<usize>,
+    pub maxmemory: Option<usize>,
+    pub maxmemory_policy: Option<MaxmemoryPolicy>,
+    pub maxmemory_samples: Option<usize>,
+    pub repl_backlog_size: Option<usize>,
+    pub replica_read_only: Option<bool>,
+    pub replica_serve_stale_data: Option<bool>,
+    pub repl_timeout: Option<u64>,
+    pub repl_ping_replica_period: Option<u64>,
+    pub repl_diskless_sync: Option<bool>,
+    pub repl_diskless_sync_delay: Option<u64>,
+    pub dir: Option<String>,
+    pub dbfilename: Option<String>,
+    pub save: Option<Vec<SaveParam>>,
+    pub appendonly: Option<bool>,
+    pub appendfsync: Option<AppendFsync>,
+    pub appendfilename: Option<String>,
+    pub aof_load_truncated: Option<bool>,
+    pub appenddirname: Option<String>,
+    pub aof_use_rdb_preamble: Option<bool>,
+    pub auto_aof_rewrite_percentage: Option<u64>,
+    pub auto_aof_rewrite_min_size: Option<u64>,
 }
 
 impl CliArgs {
@@ -11,6 +36,28 @@ impl CliArgs {
         let mut port = None;
         let mut host = None;
         let mut role = Role::Master;
+        let mut databases = None;
+        let mut maxmemory = None;
+        let mut maxmemory_policy = None;
+        let mut maxmemory_samples = None;
+        let mut repl_backlog_size = None;
+        let mut replica_read_only = None;
+        let mut replica_serve_stale_data = None;
+        let mut repl_timeout = None;
+        let mut repl_ping_replica_period = None;
+        let mut repl_diskless_sync = None;
+        let mut repl_diskless_sync_delay = None;
+        let mut dir = None;
+        let mut dbfilename = None;
+        let mut save = None;
+        let mut appendonly = None;
+        let mut appendfsync = None;
+        let mut appendfilename = None;
+        let mut aof_load_truncated = None;
+        let mut appenddirname = None;
+        let mut aof_use_rdb_preamble = None;
+        let mut auto_aof_rewrite_percentage = None;
+        let mut auto_aof_rewrite_min_size = None;
 
         let mut args = std::env::args().skip(1);
         while let Some(arg) = args.next() {
@@ -21,6 +68,72 @@ impl CliArgs {
                 "--port" => {
                     port = args.next();
                 }
+                "--databases" => {
+                    databases = args.next().map(|n| n.parse().unwrap());
+                }
+                "--maxmemory" => {
+                    maxmemory = args.next().map(|n| parse_memory(&n));
+                }
+                "--maxmemory-policy" => {
+                    maxmemory_policy = args.next().map(|p| MaxmemoryPolicy::new(&p));
+                }
+                "--maxmemory-samples" => {
+                    maxmemory_samples = args.next().map(|n| n.parse().unwrap());
+                }
+                "--repl-backlog-size" => {
+                    repl_backlog_size = args.next().map(|n| parse_memory(&n));
+                }
+                "--replica-read-only" => {
+                    replica_read_only = args.next().map(|s| parse_yes_no(&s));
+                }
+                "--replica-serve-stale-data" => {
+                    replica_serve_stale_data = args.next().map(|s| parse_yes_no(&s));
+                }
+                "--repl-timeout" => {
+                    repl_timeout = args.next().map(|n| n.parse().unwrap());
+                }
+                "--repl-ping-replica-period" => {
+                    repl_ping_replica_period = args.next().map(|n| n.parse().unwrap());
+                }
+                "--repl-diskless-sync" => {
+                    repl_diskless_sync = args.next().map(|s| parse_yes_no(&s));
+                }
+                "--repl-diskless-sync-delay" => {
+                    repl_diskless_sync_delay = args.next().map(|n| n.parse().unwrap());
+                }
+                "--dir" => {
+                    dir = args.next();
+                }
+                "--dbfilename" => {
+                    dbfilename = args.next();
+                }
+                "--save" => {
+                    save = args.next().map(|s| parse_save(&s));
+                }
+                "--appendonly" => {
+                    appendonly = args.next().map(|s| parse_yes_no(&s));
+                }
+                "--appendfsync" => {
+                    appendfsync = args.next().map(|s| AppendFsync::new(&s));
+                }
+                "--appendfilename" => {
+                    appendfilename = args.next();
+                }
+                "--aof-load-truncated" => {
+                    aof_load_truncated = args.next().map(|s| parse_yes_no(&s));
+                }
+                "--appenddirname" => {
+                    appenddirname = args.next();
+                }
+                "--aof-use-rdb-preamble" => {
+                    aof_use_rdb_preamble = args.next().map(|s| parse_yes_no(&s));
+                }
+                "--auto-aof-rewrite-percentage" => {
+                    auto_aof_rewrite_percentage = args.next().map(|n| n.parse().unwrap());
+                }
+                "--auto-aof-rewrite-min-size" => {
+                    auto_aof_rewrite_min_size = args.next().map(|n| parse_memory(&n) as u64);
+                }
                 "--replicaof" => {
                     // 1個目の引数が空白でsplitできる場合 -> --replicaof "host port"
                     // できない場合 -> --replicaof host port
@@ -47,6 +160,106 @@ impl CliArgs {
                 }
             }
         }
-        CliArgs { host, port, role }
+        CliArgs {
+            host,
+            port,
+            role,
+            databases,
+            maxmemory,
+            maxmemory_policy,
+            maxmemory_samples,
+            repl_backlog_size,
+            replica_read_only,
+            replica_serve_stale_data,
+            repl_timeout,
+            repl_ping_replica_period,
+            repl_diskless_sync,
+            repl_diskless_sync_delay,
+            dir,
+            dbfilename,
+            save,
+            appendonly,
+            appendfsync,
+            appendfilename,
+            aof_load_truncated,
+            appenddirname,
+            aof_use_rdb_preamble,
+            auto_aof_rewrite_percentage,
+            auto_aof_rewrite_min_size,
+        }
+    }
+}
+
+// "100mb" や "1gb" のような単位付きの値をバイト数に変換する
+fn parse_memory(s: &str) -> usize {
+    let s = s.to_lowercase();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => panic!("invalid memory unit: {}", unit),
+    };
+    number.parse::<usize>().unwrap() * unit
+}
+
+fn parse_yes_no(s: &str) -> bool {
+    match s.to_lowercase().as_str() {
+        "yes" => true,
+        "no" => false,
+        _ => panic!("argument must be 'yes' or 'no': {}", s),
+    }
+}
+
+// "3600 1 300 100" のように <seconds> <changes> を並べたもの。空文字列なら保存しない
+fn parse_save(s: &str) -> Vec<SaveParam> {
+    let values = s
+        .split_whitespace()
+        .map(|n| n.parse().unwrap())
+        .collect::<Vec<u64>>();
+    if !values.len().is_multiple_of(2) {
+        panic!("invalid save parameters: {}", s);
+    }
+    values
+        .chunks(2)
+        .map(|pair| SaveParam {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("1024"), 1024);
+        assert_eq!(parse_memory("1k"), 1000);
+        assert_eq!(parse_memory("2mb"), 2 * 1024 * 1024);
+        assert_eq!(parse_memory("1GB"), 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_parse_save() {
+        assert_eq!(parse_save(""), vec![]);
+        assert_eq!(
+            parse_save("3600 1 300 100"),
+            vec![
+                SaveParam {
+                    seconds: 3600,
+                    changes: 1
+                },
+                SaveParam {
+                    seconds: 300,
+                    changes: 100
+                },
+            ]
+        );
     }
 }
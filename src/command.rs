@@ -1,6 +1,6 @@
 use crate::resp::RESP;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum RedisCommand {
     Echo(String),
     Ping,
@@ -22,6 +22,93 @@ pub enum RedisCommand {
         master_replid: String,
         master_repl_offset: i64,
     },
+    Del {
+        keys: Vec<String>,
+    },
+    Unlink {
+        keys: Vec<String>,
+    },
+    Exists {
+        keys: Vec<String>,
+    },
+    Type {
+        key: String,
+    },
+    Rename {
+        key: String,
+        newkey: String,
+    },
+    Renamenx {
+        key: String,
+        newkey: String,
+    },
+    Copy {
+        source: String,
+        destination: String,
+        db: Option<usize>,
+        replace: bool,
+    },
+    Dump {
+        key: String,
+    },
+    // ttl は ms。0 なら有効期限なし
+    Restore {
+        key: String,
+        ttl: i64,
+        payload: String,
+        options: Vec<RestoreCommandOption>,
+    },
+    // KEYS を使うときは key を空文字列にする。timeout は ms
+    Migrate {
+        host: String,
+        port: String,
+        key: String,
+        destination_db: usize,
+        timeout: i64,
+        options: Vec<MigrateCommandOption>,
+    },
+    Touch {
+        keys: Vec<String>,
+    },
+    Randomkey,
+    Dbsize,
+    Keys {
+        pattern: String,
+    },
+    Scan {
+        cursor: u64,
+        options: Vec<ScanCommandOption>,
+    },
+    Select {
+        index: usize,
+    },
+    Move {
+        key: String,
+        db: usize,
+    },
+    Swapdb {
+        index1: usize,
+        index2: usize,
+    },
+    Flushdb {
+        mode: FlushMode,
+    },
+    Flushall {
+        mode: FlushMode,
+    },
+    Wait {
+        numreplicas: usize,
+        timeout: u64, // milliseconds
+    },
+    Role,
+    Save,
+    Bgsave,
+    Lastsave,
+    Bgrewriteaof,
+    // None は REPLICAOF NO ONE
+    Replicaof {
+        master: Option<(String, String)>,
+    },
 }
 
 impl RedisCommand {
@@ -29,7 +116,7 @@ impl RedisCommand {
         if let RESP::Array(array) = resp {
             let mut iter = array.iter();
             match iter.next().unwrap() {
-                RESP::BulkStrings(command) => match command.as_str() {
+                RESP::BulkStrings(command) => match command.to_uppercase().as_str() {
                     "PING" => RedisCommand::Ping,
                     "ECHO" => Self::new_echo(&mut iter),
                     "SET" => Self::new_set(&mut iter),
@@ -37,6 +124,68 @@ impl RedisCommand {
                     "INFO" => Self::new_info(&mut iter),
                     "REPLCONF" => Self::new_replconf(&mut iter),
                     "PSYNC" => Self::new_psync(&mut iter),
+                    "DEL" => RedisCommand::Del {
+                        keys: Self::new_keys(&mut iter),
+                    },
+                    "UNLINK" => RedisCommand::Unlink {
+                        keys: Self::new_keys(&mut iter),
+                    },
+                    "EXISTS" => RedisCommand::Exists {
+                        keys: Self::new_keys(&mut iter),
+                    },
+                    "TYPE" => RedisCommand::Type {
+                        key: next_string(&mut iter),
+                    },
+                    "RENAME" => RedisCommand::Rename {
+                        key: next_string(&mut iter),
+                        newkey: next_string(&mut iter),
+                    },
+                    "RENAMENX" => RedisCommand::Renamenx {
+                        key: next_string(&mut iter),
+                        newkey: next_string(&mut iter),
+                    },
+                    "COPY" => Self::new_copy(&mut iter),
+                    "DUMP" => RedisCommand::Dump {
+                        key: next_string(&mut iter),
+                    },
+                    "RESTORE" => Self::new_restore(&mut iter),
+                    "MIGRATE" => Self::new_migrate(&mut iter),
+                    "TOUCH" => RedisCommand::Touch {
+                        keys: Self::new_keys(&mut iter),
+                    },
+                    "RANDOMKEY" => RedisCommand::Randomkey,
+                    "DBSIZE" => RedisCommand::Dbsize,
+                    "KEYS" => RedisCommand::Keys {
+                        pattern: next_string(&mut iter),
+                    },
+                    "SCAN" => Self::new_scan(&mut iter),
+                    "SELECT" => RedisCommand::Select {
+                        index: next_string(&mut iter).parse().unwrap(),
+                    },
+                    "MOVE" => RedisCommand::Move {
+                        key: next_string(&mut iter),
+                        db: next_string(&mut iter).parse().unwrap(),
+                    },
+                    "SWAPDB" => RedisCommand::Swapdb {
+                        index1: next_string(&mut iter).parse().unwrap(),
+                        index2: next_string(&mut iter).parse().unwrap(),
+                    },
+                    "FLUSHDB" => RedisCommand::Flushdb {
+                        mode: FlushMode::new(iter.next().map(as_string).as_deref()),
+                    },
+                    "FLUSHALL" => RedisCommand::Flushall {
+                        mode: FlushMode::new(iter.next().map(as_string).as_deref()),
+                    },
+                    "WAIT" => RedisCommand::Wait {
+                        numreplicas: next_string(&mut iter).parse().unwrap(),
+                        timeout: next_string(&mut iter).parse().unwrap(),
+                    },
+                    "ROLE" => RedisCommand::Role,
+                    "SAVE" => RedisCommand::Save,
+                    "BGSAVE" => RedisCommand::Bgsave,
+                    "LASTSAVE" => RedisCommand::Lastsave,
+                    "BGREWRITEAOF" => RedisCommand::Bgrewriteaof,
+                    "REPLICAOF" | "SLAVEOF" => Self::new_replicaof(&mut iter),
                     _ => panic!("unknown command"),
                 },
                 _ => panic!("invalid command"),
@@ -46,6 +195,56 @@ impl RedisCommand {
         }
     }
 
+    // レプリケーションや永続化の対象になる、データを変更するコマンド
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            RedisCommand::Set { .. }
+                | RedisCommand::Del { .. }
+                | RedisCommand::Unlink { .. }
+                | RedisCommand::Rename { .. }
+                | RedisCommand::Renamenx { .. }
+                | RedisCommand::Copy { .. }
+                | RedisCommand::Restore { .. }
+                | RedisCommand::Migrate { .. }
+                | RedisCommand::Move { .. }
+                | RedisCommand::Swapdb { .. }
+                | RedisCommand::Flushdb { .. }
+                | RedisCommand::Flushall { .. }
+        )
+    }
+
+    // レプリカがマスターとのリンクを失っていて replica-serve-stale-data が no でも受け付けるコマンド
+    pub fn is_stale_ok(&self) -> bool {
+        matches!(
+            self,
+            RedisCommand::Ping
+                | RedisCommand::Info { .. }
+                | RedisCommand::Replconf { .. }
+                | RedisCommand::Replicaof { .. }
+                | RedisCommand::Role
+        )
+    }
+
+    // maxmemory を超えているときに拒否する、メモリを増やしうるコマンド
+    pub fn is_denyoom(&self) -> bool {
+        matches!(
+            self,
+            RedisCommand::Set { .. } | RedisCommand::Copy { .. } | RedisCommand::Restore { .. }
+        )
+    }
+
+    fn new_replicaof(iter: &mut std::slice::Iter<RESP>) -> Self {
+        let host = next_string(iter);
+        let port = next_string(iter);
+        let master = if host.to_uppercase() == "NO" && port.to_uppercase() == "ONE" {
+            None
+        } else {
+            Some((host, port))
+        };
+        RedisCommand::Replicaof { master }
+    }
+
     fn new_echo(iter: &mut std::slice::Iter<RESP>) -> Self {
         let value = match iter.next().unwrap() {
             RESP::BulkStrings(value) => value,
@@ -64,21 +263,16 @@ impl RedisCommand {
             _ => panic!("invalid command"),
         };
         let mut options = vec![];
-        loop {
-            match iter.next() {
-                Some(option) => {
-                    let value = match iter.next().unwrap() {
-                        RESP::BulkStrings(value) => value,
-                        _ => panic!("invalid command"),
-                    };
-                    let option = match option {
-                        RESP::BulkStrings(option) => option,
-                        _ => panic!("invalid command"),
-                    };
-                    options.push(SetCommandOption::new(option, value));
-                }
-                None => break,
-            }
+        while let Some(option) = iter.next() {
+            let value = match iter.next().unwrap() {
+                RESP::BulkStrings(value) => value,
+                _ => panic!("invalid command"),
+            };
+            let option = match option {
+                RESP::BulkStrings(option) => option,
+                _ => panic!("invalid command"),
+            };
+            options.push(SetCommandOption::new(option, value));
         }
         RedisCommand::Set {
             key: key.to_string(),
@@ -115,6 +309,16 @@ impl RedisCommand {
             RESP::BulkStrings(arg) => arg,
             _ => panic!("invalid command"),
         };
+        // REPLCONF capa eof capa psync2 のように capa は複数並べられる
+        if command.to_lowercase() == "capa" {
+            let mut capa = vec![arg.to_string()];
+            while let (Some(_), Some(arg)) = (iter.next(), iter.next()) {
+                capa.push(as_string(arg));
+            }
+            return RedisCommand::Replconf {
+                command: ReplconfCommand::Capa(capa),
+            };
+        }
         RedisCommand::Replconf {
             command: ReplconfCommand::new(command, arg),
         }
@@ -135,6 +339,96 @@ impl RedisCommand {
         }
     }
 
+    fn new_keys(iter: &mut std::slice::Iter<RESP>) -> Vec<String> {
+        let keys = iter.map(as_string).collect::<Vec<_>>();
+        if keys.is_empty() {
+            panic!("invalid command");
+        }
+        keys
+    }
+
+    fn new_copy(iter: &mut std::slice::Iter<RESP>) -> RedisCommand {
+        let source = next_string(iter);
+        let destination = next_string(iter);
+        let mut db = None;
+        let mut replace = false;
+        while let Some(option) = iter.next() {
+            match as_string(option).to_uppercase().as_str() {
+                "DB" => db = Some(next_string(iter).parse().unwrap()),
+                "REPLACE" => replace = true,
+                _ => panic!("unknown option"),
+            }
+        }
+        RedisCommand::Copy {
+            source,
+            destination,
+            db,
+            replace,
+        }
+    }
+
+    fn new_restore(iter: &mut std::slice::Iter<RESP>) -> RedisCommand {
+        let key = next_string(iter);
+        let ttl = next_string(iter).parse().unwrap();
+        let payload = next_string(iter);
+        let mut options = vec![];
+        while let Some(option) = iter.next() {
+            options.push(match as_string(option).to_uppercase().as_str() {
+                "REPLACE" => RestoreCommandOption::Replace,
+                "ABSTTL" => RestoreCommandOption::Absttl,
+                "IDLETIME" => RestoreCommandOption::Idletime(next_string(iter).parse().unwrap()),
+                "FREQ" => RestoreCommandOption::Freq(next_string(iter).parse().unwrap()),
+                _ => panic!("unknown option"),
+            });
+        }
+        RedisCommand::Restore {
+            key,
+            ttl,
+            payload,
+            options,
+        }
+    }
+
+    fn new_migrate(iter: &mut std::slice::Iter<RESP>) -> RedisCommand {
+        let host = next_string(iter);
+        let port = next_string(iter);
+        let key = next_string(iter);
+        let destination_db = next_string(iter).parse().unwrap();
+        let timeout = next_string(iter).parse().unwrap();
+        let mut options = vec![];
+        while let Some(option) = iter.next() {
+            options.push(match as_string(option).to_uppercase().as_str() {
+                "COPY" => MigrateCommandOption::Copy,
+                "REPLACE" => MigrateCommandOption::Replace,
+                "AUTH" => MigrateCommandOption::Auth(next_string(iter)),
+                "AUTH2" => MigrateCommandOption::Auth2(next_string(iter), next_string(iter)),
+                // KEYS 以降はすべてキー
+                "KEYS" => MigrateCommandOption::Keys(iter.by_ref().map(as_string).collect()),
+                _ => panic!("unknown option"),
+            });
+        }
+        RedisCommand::Migrate {
+            host,
+            port,
+            key,
+            destination_db,
+            timeout,
+            options,
+        }
+    }
+
+    fn new_scan(iter: &mut std::slice::Iter<RESP>) -> RedisCommand {
+        let cursor = next_string(iter).parse().unwrap();
+        let mut options = vec![];
+        while let Some(option) = iter.next() {
+            options.push(ScanCommandOption::new(
+                &as_string(option),
+                &next_string(iter),
+            ));
+        }
+        RedisCommand::Scan { cursor, options }
+    }
+
     pub fn to_resp(self) -> RESP {
         match self {
             RedisCommand::Ping => RESP::Array(vec![RESP::BulkStrings("PING".to_string())]),
@@ -158,6 +452,10 @@ impl RedisCommand {
                             ret.push(RESP::BulkStrings("px".to_string()));
                             ret.push(RESP::BulkStrings(px.to_string()));
                         }
+                        SetCommandOption::Pxat(pxat) => {
+                            ret.push(RESP::BulkStrings("pxat".to_string()));
+                            ret.push(RESP::BulkStrings(pxat.to_string()));
+                        }
                     }
                 }
                 RESP::Array(ret)
@@ -172,6 +470,22 @@ impl RedisCommand {
                     RESP::BulkStrings("INFO".to_string()),
                     RESP::BulkStrings("replication".to_string()),
                 ]),
+                InfoSection::Keyspace => RESP::Array(vec![
+                    RESP::BulkStrings("INFO".to_string()),
+                    RESP::BulkStrings("keyspace".to_string()),
+                ]),
+                InfoSection::Memory => RESP::Array(vec![
+                    RESP::BulkStrings("INFO".to_string()),
+                    RESP::BulkStrings("memory".to_string()),
+                ]),
+                InfoSection::Stats => RESP::Array(vec![
+                    RESP::BulkStrings("INFO".to_string()),
+                    RESP::BulkStrings("stats".to_string()),
+                ]),
+                InfoSection::Persistence => RESP::Array(vec![
+                    RESP::BulkStrings("INFO".to_string()),
+                    RESP::BulkStrings("persistence".to_string()),
+                ]),
             },
             RedisCommand::Replconf { command } => match command {
                 ReplconfCommand::ListeningPort(port) => RESP::Array(vec![
@@ -179,10 +493,21 @@ impl RedisCommand {
                     RESP::BulkStrings("listening-port".to_string()),
                     RESP::BulkStrings(port),
                 ]),
-                ReplconfCommand::Capa(capa) => RESP::Array(vec![
+                ReplconfCommand::Capa(capa) => bulk_array(
+                    "REPLCONF",
+                    capa.into_iter()
+                        .flat_map(|capa| ["capa".to_string(), capa])
+                        .collect(),
+                ),
+                ReplconfCommand::GetAck(arg) => RESP::Array(vec![
                     RESP::BulkStrings("REPLCONF".to_string()),
-                    RESP::BulkStrings("capa".to_string()),
-                    RESP::BulkStrings(capa),
+                    RESP::BulkStrings("GETACK".to_string()),
+                    RESP::BulkStrings(arg),
+                ]),
+                ReplconfCommand::Ack(offset) => RESP::Array(vec![
+                    RESP::BulkStrings("REPLCONF".to_string()),
+                    RESP::BulkStrings("ACK".to_string()),
+                    RESP::BulkStrings(offset.to_string()),
                 ]),
             },
             RedisCommand::Psync {
@@ -193,51 +518,263 @@ impl RedisCommand {
                 RESP::BulkStrings(master_replid),
                 RESP::BulkStrings(master_repl_offset.to_string()),
             ]),
+            RedisCommand::Del { keys } => bulk_array("DEL", keys),
+            RedisCommand::Unlink { keys } => bulk_array("UNLINK", keys),
+            RedisCommand::Exists { keys } => bulk_array("EXISTS", keys),
+            RedisCommand::Type { key } => bulk_array("TYPE", vec![key]),
+            RedisCommand::Rename { key, newkey } => bulk_array("RENAME", vec![key, newkey]),
+            RedisCommand::Renamenx { key, newkey } => bulk_array("RENAMENX", vec![key, newkey]),
+            RedisCommand::Copy {
+                source,
+                destination,
+                db,
+                replace,
+            } => {
+                let mut args = vec![source, destination];
+                if let Some(db) = db {
+                    args.extend(["DB".to_string(), db.to_string()]);
+                }
+                if replace {
+                    args.push("REPLACE".to_string());
+                }
+                bulk_array("COPY", args)
+            }
+            RedisCommand::Dump { key } => bulk_array("DUMP", vec![key]),
+            RedisCommand::Restore {
+                key,
+                ttl,
+                payload,
+                options,
+            } => {
+                let mut args = vec![key, ttl.to_string(), payload];
+                for option in options {
+                    match option {
+                        RestoreCommandOption::Replace => args.push("REPLACE".to_string()),
+                        RestoreCommandOption::Absttl => args.push("ABSTTL".to_string()),
+                        RestoreCommandOption::Idletime(seconds) => {
+                            args.extend(["IDLETIME".to_string(), seconds.to_string()])
+                        }
+                        RestoreCommandOption::Freq(freq) => {
+                            args.extend(["FREQ".to_string(), freq.to_string()])
+                        }
+                    }
+                }
+                bulk_array("RESTORE", args)
+            }
+            RedisCommand::Migrate {
+                host,
+                port,
+                key,
+                destination_db,
+                timeout,
+                options,
+            } => {
+                let mut args = vec![
+                    host,
+                    port,
+                    key,
+                    destination_db.to_string(),
+                    timeout.to_string(),
+                ];
+                for option in options {
+                    match option {
+                        MigrateCommandOption::Copy => args.push("COPY".to_string()),
+                        MigrateCommandOption::Replace => args.push("REPLACE".to_string()),
+                        MigrateCommandOption::Auth(password) => {
+                            args.extend(["AUTH".to_string(), password])
+                        }
+                        MigrateCommandOption::Auth2(username, password) => {
+                            args.extend(["AUTH2".to_string(), username, password])
+                        }
+                        MigrateCommandOption::Keys(keys) => {
+                            args.push("KEYS".to_string());
+                            args.extend(keys);
+                        }
+                    }
+                }
+                bulk_array("MIGRATE", args)
+            }
+            RedisCommand::Touch { keys } => bulk_array("TOUCH", keys),
+            RedisCommand::Randomkey => bulk_array("RANDOMKEY", vec![]),
+            RedisCommand::Dbsize => bulk_array("DBSIZE", vec![]),
+            RedisCommand::Keys { pattern } => bulk_array("KEYS", vec![pattern]),
+            RedisCommand::Scan { cursor, options } => {
+                let mut args = vec![cursor.to_string()];
+                for option in options {
+                    match option {
+                        ScanCommandOption::Match(pattern) => {
+                            args.extend(["MATCH".to_string(), pattern])
+                        }
+                        ScanCommandOption::Count(count) => {
+                            args.extend(["COUNT".to_string(), count.to_string()])
+                        }
+                        ScanCommandOption::Type(type_name) => {
+                            args.extend(["TYPE".to_string(), type_name])
+                        }
+                    }
+                }
+                bulk_array("SCAN", args)
+            }
+            RedisCommand::Select { index } => bulk_array("SELECT", vec![index.to_string()]),
+            RedisCommand::Move { key, db } => bulk_array("MOVE", vec![key, db.to_string()]),
+            RedisCommand::Swapdb { index1, index2 } => {
+                bulk_array("SWAPDB", vec![index1.to_string(), index2.to_string()])
+            }
+            RedisCommand::Flushdb { mode } => bulk_array("FLUSHDB", vec![mode.to_string()]),
+            RedisCommand::Flushall { mode } => bulk_array("FLUSHALL", vec![mode.to_string()]),
+            RedisCommand::Wait {
+                numreplicas,
+                timeout,
+            } => bulk_array("WAIT", vec![numreplicas.to_string(), timeout.to_string()]),
+            RedisCommand::Role => bulk_array("ROLE", vec![]),
+            RedisCommand::Save => bulk_array("SAVE", vec![]),
+            RedisCommand::Bgsave => bulk_array("BGSAVE", vec![]),
+            RedisCommand::Lastsave => bulk_array("LASTSAVE", vec![]),
+            RedisCommand::Bgrewriteaof => bulk_array("BGREWRITEAOF", vec![]),
+            RedisCommand::Replicaof { master } => match master {
+                Some((host, port)) => bulk_array("REPLICAOF", vec![host, port]),
+                None => bulk_array("REPLICAOF", vec!["NO".to_string(), "ONE".to_string()]),
+            },
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+fn as_string(resp: &RESP) -> String {
+    match resp {
+        RESP::BulkStrings(s) => s.to_string(),
+        _ => panic!("invalid command"),
+    }
+}
+
+fn next_string(iter: &mut std::slice::Iter<RESP>) -> String {
+    as_string(iter.next().unwrap())
+}
+
+fn bulk_array(command: &str, args: Vec<String>) -> RESP {
+    let mut ret = vec![RESP::BulkStrings(command.to_string())];
+    ret.extend(args.into_iter().map(RESP::BulkStrings));
+    RESP::Array(ret)
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum SetCommandOption {
-    Px(u128), // milliseconds
+    Px(u128),   // milliseconds
+    Pxat(u128), // UNIX time in milliseconds
 }
 
 impl SetCommandOption {
     pub fn new(option: &str, value: &str) -> SetCommandOption {
         match option {
             "px" => SetCommandOption::Px(value.parse().unwrap()),
+            "pxat" => SetCommandOption::Pxat(value.parse().unwrap()),
             _ => panic!("unknown option"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+pub enum ScanCommandOption {
+    Match(String),
+    Count(usize),
+    Type(String),
+}
+
+impl ScanCommandOption {
+    pub fn new(option: &str, value: &str) -> ScanCommandOption {
+        match option.to_uppercase().as_str() {
+            "MATCH" => ScanCommandOption::Match(value.to_string()),
+            "COUNT" => ScanCommandOption::Count(value.parse().unwrap()),
+            "TYPE" => ScanCommandOption::Type(value.to_string()),
+            _ => panic!("unknown option"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RestoreCommandOption {
+    Replace,
+    // ttl を UNIX 時刻 (ms) として扱う
+    Absttl,
+    // LRU 用の最後のアクセスからの秒数と、LFU 用のカウンタ
+    Idletime(i64),
+    Freq(i64),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MigrateCommandOption {
+    // 移行元のキーを消さない
+    Copy,
+    Replace,
+    Auth(String),
+    // ユーザー名とパスワード
+    Auth2(String, String),
+    Keys(Vec<String>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FlushMode {
+    Sync,
+    Async,
+}
+
+impl FlushMode {
+    pub fn new(maybe_str: Option<&str>) -> Self {
+        match maybe_str.map(|s| s.to_uppercase()).as_deref() {
+            Some("SYNC") | None => FlushMode::Sync,
+            Some("ASYNC") => FlushMode::Async,
+            _ => panic!("unknown option"),
+        }
+    }
+}
+
+impl std::fmt::Display for FlushMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FlushMode::Sync => write!(f, "SYNC"),
+            FlushMode::Async => write!(f, "ASYNC"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum InfoSection {
     All,
     Replication,
+    Keyspace,
+    Memory,
+    Stats,
+    Persistence,
 }
 
 impl InfoSection {
     pub fn new(maybe_str: Option<&str>) -> Self {
         match maybe_str {
             Some("replication") => InfoSection::Replication,
+            Some("keyspace") => InfoSection::Keyspace,
+            Some("memory") => InfoSection::Memory,
+            Some("stats") => InfoSection::Stats,
+            Some("persistence") => InfoSection::Persistence,
             None => InfoSection::All,
             _ => panic!("unknown section"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ReplconfCommand {
     ListeningPort(String),
-    Capa(String),
+    Capa(Vec<String>),
+    GetAck(String),
+    Ack(u64),
 }
 
 impl ReplconfCommand {
     pub fn new(command: &str, arg: &str) -> Self {
-        match command {
+        match command.to_lowercase().as_str() {
             "listening-port" => ReplconfCommand::ListeningPort(arg.to_string()),
-            "capa" => ReplconfCommand::Capa(arg.to_string()),
+            "capa" => ReplconfCommand::Capa(vec![arg.to_string()]),
+            "getack" => ReplconfCommand::GetAck(arg.to_string()),
+            "ack" => ReplconfCommand::Ack(arg.parse().unwrap()),
             _ => panic!("unknown command"),
         }
     }
@@ -338,13 +875,242 @@ mod tests {
             RESP::BulkStrings("REPLCONF".to_string()),
             RESP::BulkStrings("capa".to_string()),
             RESP::BulkStrings("eof".to_string()),
+            RESP::BulkStrings("capa".to_string()),
+            RESP::BulkStrings("psync2".to_string()),
         ]);
+        let command = RedisCommand::new(resp.clone());
         assert_eq!(
-            RedisCommand::new(resp),
+            command,
             RedisCommand::Replconf {
-                command: ReplconfCommand::Capa("eof".to_string())
+                command: ReplconfCommand::Capa(vec!["eof".to_string(), "psync2".to_string()])
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("REPLCONF".to_string()),
+            RESP::BulkStrings("GETACK".to_string()),
+            RESP::BulkStrings("*".to_string()),
+        ]);
+        let command = RedisCommand::new(resp.clone());
+        assert_eq!(
+            command,
+            RedisCommand::Replconf {
+                command: ReplconfCommand::GetAck("*".to_string())
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("REPLCONF".to_string()),
+            RESP::BulkStrings("ACK".to_string()),
+            RESP::BulkStrings("154".to_string()),
+        ]);
+        let command = RedisCommand::new(resp.clone());
+        assert_eq!(
+            command,
+            RedisCommand::Replconf {
+                command: ReplconfCommand::Ack(154)
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+    }
+
+    #[test]
+    fn test_new_del() {
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("del".to_string()),
+            RESP::BulkStrings("key1".to_string()),
+            RESP::BulkStrings("key2".to_string()),
+        ]);
+        assert_eq!(
+            RedisCommand::new(resp),
+            RedisCommand::Del {
+                keys: vec!["key1".to_string(), "key2".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_new_copy() {
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("COPY".to_string()),
+            RESP::BulkStrings("src".to_string()),
+            RESP::BulkStrings("dst".to_string()),
+            RESP::BulkStrings("DB".to_string()),
+            RESP::BulkStrings("3".to_string()),
+            RESP::BulkStrings("REPLACE".to_string()),
+        ]);
+        let command = RedisCommand::new(resp.clone());
+        assert_eq!(
+            command,
+            RedisCommand::Copy {
+                source: "src".to_string(),
+                destination: "dst".to_string(),
+                db: Some(3),
+                replace: true
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+    }
+
+    #[test]
+    fn test_new_restore() {
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("RESTORE".to_string()),
+            RESP::BulkStrings("key".to_string()),
+            RESP::BulkStrings("1000".to_string()),
+            RESP::BulkStrings("\x00\x01v".to_string()),
+            RESP::BulkStrings("REPLACE".to_string()),
+            RESP::BulkStrings("ABSTTL".to_string()),
+            RESP::BulkStrings("IDLETIME".to_string()),
+            RESP::BulkStrings("60".to_string()),
+        ]);
+        let command = RedisCommand::new(resp.clone());
+        assert_eq!(
+            command,
+            RedisCommand::Restore {
+                key: "key".to_string(),
+                ttl: 1000,
+                payload: "\x00\x01v".to_string(),
+                options: vec![
+                    RestoreCommandOption::Replace,
+                    RestoreCommandOption::Absttl,
+                    RestoreCommandOption::Idletime(60),
+                ],
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+    }
+
+    #[test]
+    fn test_new_migrate() {
+        let resp = RESP::Array(
+            [
+                "MIGRATE",
+                "127.0.0.1",
+                "6380",
+                "",
+                "2",
+                "5000",
+                "COPY",
+                "AUTH2",
+                "user",
+                "pass",
+                "KEYS",
+                "k1",
+                "k2",
+            ]
+            .iter()
+            .map(|s| RESP::BulkStrings(s.to_string()))
+            .collect(),
+        );
+        let command = RedisCommand::new(resp.clone());
+        assert_eq!(
+            command,
+            RedisCommand::Migrate {
+                host: "127.0.0.1".to_string(),
+                port: "6380".to_string(),
+                key: "".to_string(),
+                destination_db: 2,
+                timeout: 5000,
+                options: vec![
+                    MigrateCommandOption::Copy,
+                    MigrateCommandOption::Auth2("user".to_string(), "pass".to_string()),
+                    MigrateCommandOption::Keys(vec!["k1".to_string(), "k2".to_string()]),
+                ],
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+    }
+
+    #[test]
+    fn test_new_scan() {
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("SCAN".to_string()),
+            RESP::BulkStrings("17".to_string()),
+            RESP::BulkStrings("MATCH".to_string()),
+            RESP::BulkStrings("user:*".to_string()),
+            RESP::BulkStrings("COUNT".to_string()),
+            RESP::BulkStrings("100".to_string()),
+            RESP::BulkStrings("TYPE".to_string()),
+            RESP::BulkStrings("string".to_string()),
+        ]);
+        let command = RedisCommand::new(resp.clone());
+        assert_eq!(
+            command,
+            RedisCommand::Scan {
+                cursor: 17,
+                options: vec![
+                    ScanCommandOption::Match("user:*".to_string()),
+                    ScanCommandOption::Count(100),
+                    ScanCommandOption::Type("string".to_string()),
+                ]
+            }
+        );
+        assert_eq!(command.to_resp(), resp);
+    }
+
+    #[test]
+    fn test_new_flushdb() {
+        let resp = RESP::Array(vec![RESP::BulkStrings("FLUSHDB".to_string())]);
+        assert_eq!(
+            RedisCommand::new(resp),
+            RedisCommand::Flushdb {
+                mode: FlushMode::Sync
             }
         );
+
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("FLUSHALL".to_string()),
+            RESP::BulkStrings("async".to_string()),
+        ]);
+        assert_eq!(
+            RedisCommand::new(resp),
+            RedisCommand::Flushall {
+                mode: FlushMode::Async
+            }
+        );
+    }
+
+    #[test]
+    fn test_new_wait() {
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("WAIT".to_string()),
+            RESP::BulkStrings("1".to_string()),
+            RESP::BulkStrings("500".to_string()),
+        ]);
+        assert_eq!(
+            RedisCommand::new(resp),
+            RedisCommand::Wait {
+                numreplicas: 1,
+                timeout: 500
+            }
+        );
+    }
+
+    #[test]
+    fn test_new_replicaof() {
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("REPLICAOF".to_string()),
+            RESP::BulkStrings("127.0.0.1".to_string()),
+            RESP::BulkStrings("6380".to_string()),
+        ]);
+        assert_eq!(
+            RedisCommand::new(resp),
+            RedisCommand::Replicaof {
+                master: Some(("127.0.0.1".to_string(), "6380".to_string()))
+            }
+        );
+        let resp = RESP::Array(vec![
+            RESP::BulkStrings("slaveof".to_string()),
+            RESP::BulkStrings("no".to_string()),
+            RESP::BulkStrings("one".to_string()),
+        ]);
+        assert_eq!(
+            RedisCommand::new(resp),
+            RedisCommand::Replicaof { master: None }
+        );
     }
 
     #[test]
@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+// キーに格納される値。文字列は resp::bytes_to_string と同じくバイト列を latin-1 として持つ
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    String(String),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    ZSet(HashMap<String, f64>),
+    Hash(HashMap<String, String>),
+    Stream(Stream),
+}
+
+impl Data {
+    // TYPE コマンドが返す名前
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Data::String(_) => "string",
+            Data::List(_) => "list",
+            Data::Set(_) => "set",
+            Data::ZSet(_) => "zset",
+            Data::Hash(_) => "hash",
+            Data::Stream(_) => "stream",
+        }
+    }
+
+    // maxmemory の計算に使う、中身のおおよそのバイト数
+    pub fn size(&self) -> usize {
+        match self {
+            Data::String(s) => s.len(),
+            Data::List(list) => list.iter().map(String::len).sum(),
+            Data::Set(set) => set.iter().map(String::len).sum(),
+            Data::ZSet(zset) => zset.keys().map(|member| member.len() + 8).sum(),
+            Data::Hash(hash) => hash.iter().map(|(k, v)| k.len() + v.len()).sum(),
+            Data::Stream(stream) => stream
+                .entries
+                .values()
+                .flatten()
+                .map(|(k, v)| k.len() + v.len() + 16)
+                .sum(),
+        }
+    }
+}
+
+// スコア順 (同点ならメンバーの辞書順) に並べたメンバー
+pub fn zset_sorted(zset: &HashMap<String, f64>) -> Vec<(&String, f64)> {
+    let mut members = zset
+        .iter()
+        .map(|(member, score)| (member, *score))
+        .collect::<Vec<_>>();
+    members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+    members
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    pub last_id: StreamId,
+    // これまでに追加されたエントリの総数と、削除されたうち最大の ID
+    pub entries_added: u64,
+    pub max_deleted_id: StreamId,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: String,
+    pub last_id: StreamId,
+    // 読み出したエントリの数。分からなければ None
+    pub entries_read: Option<u64>,
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+// まだ XACK されていない配信済みのエントリ
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub consumer: Option<String>,
+    // UNIX 時刻 (ms)
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    pub name: String,
+    // UNIX 時刻 (ms)
+    pub seen_time: u64,
+    pub active_time: u64,
+}
@@ -0,0 +1,162 @@
+use crate::resp::string_to_bytes;
+
+// Redis の stringmatchlen 互換のglobマッチ
+// KEYS / SCAN だけでなく PSUBSCRIBE や ACL のキーパターンからも使う想定
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    // 文字列はバイト列を latin-1 として持っているので、1文字を1バイトに戻して比べる
+    match_bytes(&string_to_bytes(pattern), &string_to_bytes(string))
+}
+
+// '*' は最後に出てきたものだけを覚えておき、以降で食い違ったら、その '*' がもう1バイト多く
+// マッチしたものとしてやり直す。後ろの '*' は前の '*' のマッチの仕方の違いを吸収できるので、
+// それより前の '*' まで戻る必要はなく、パターン長 × 文字列長で済む
+fn match_bytes(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // 最後の '*' の直後のパターンの位置と、その '*' がマッチした範囲の終わりの文字列の位置
+    let mut star = None;
+    loop {
+        if pattern.get(p) == Some(&b'*') {
+            // 連続する '*' は1つとみなす
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+            star = Some((p, s));
+            continue;
+        }
+        if p == pattern.len() && s == string.len() {
+            return true;
+        }
+        if p < pattern.len() {
+            if let Some(rest) = match_one(&pattern[p..], string.get(s).copied()) {
+                p = pattern.len() - rest.len();
+                s += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((star_p, star_s)) if star_s < string.len() => {
+                star = Some((star_p, star_s + 1));
+                p = star_p;
+                s = star_s + 1;
+            }
+            _ => return false,
+        }
+    }
+}
+
+// パターンの先頭の '*' 以外の1要素を c と比べ、マッチすれば残りのパターンを返す
+fn match_one(pattern: &[u8], c: Option<u8>) -> Option<&[u8]> {
+    let c = c?;
+    match pattern {
+        [b'?', rest @ ..] => Some(rest),
+        [b'[', rest @ ..] => {
+            let (matched, rest) = match_class(rest, Some(c));
+            matched.then_some(rest)
+        }
+        [b'\\', escaped, rest @ ..] => (*escaped == c).then_some(rest),
+        [p, rest @ ..] => (*p == c).then_some(rest),
+        [] => None,
+    }
+}
+
+// '[' の直後から ']' までを評価し、マッチしたかどうかと ']' より後ろのパターンを返す
+fn match_class(mut pattern: &[u8], c: Option<u8>) -> (bool, &[u8]) {
+    let c = match c {
+        Some(c) => c,
+        None => return (false, pattern),
+    };
+    let not = pattern.first() == Some(&b'^');
+    if not {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                if *escaped == c {
+                    matched = true;
+                }
+                pattern = rest;
+            }
+            // Redis と同じく ']' の直前の '-' も範囲を表し、その ']' は範囲の終わりになる
+            [start, b'-', end, rest @ ..] => {
+                let (start, end) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                if start <= c && c <= end {
+                    matched = true;
+                }
+                pattern = rest;
+            }
+            [p, rest @ ..] => {
+                if *p == c {
+                    matched = true;
+                }
+                pattern = rest;
+            }
+        }
+    }
+    (matched != not, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h*llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(glob_match("h[b-a]llo", "hallo"));
+        assert!(!glob_match("h[a-b]llo", "hcllo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("[\\]]", "]"));
+        // "[a-]" は ']' から 'a' までの範囲で、クラスは閉じていない
+        assert!(glob_match("[a-]", "]"));
+        assert!(glob_match("[a-]", "_"));
+        assert!(!glob_match("[a-]", "-"));
+        assert!(glob_match("[a-]x]", "x"));
+        assert!(glob_match("user:*:name", "user:1000:name"));
+        assert!(!glob_match("user:*:name", "user:1000:age"));
+        assert!(!glob_match("abc", "abcd"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn test_glob_match_binary() {
+        // 0x80 以上のバイトも1文字として扱う
+        assert!(glob_match("?", "\u{ff}"));
+        assert!(glob_match("k[\u{80}-\u{ff}]", "k\u{90}"));
+        assert!(!glob_match("k[\u{80}-\u{8f}]", "k\u{90}"));
+        assert!(glob_match("*\u{ff}", "a\u{ff}"));
+    }
+
+    #[test]
+    fn test_glob_match_many_stars() {
+        // 素朴な再帰だと '*' の数に対して指数時間かかるパターン
+        let string = "a".repeat(100);
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*b", &string));
+        assert!(glob_match("*a*a*a*a*a*a*a*a*a*a*a*a*", &string));
+    }
+}
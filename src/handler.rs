@@ -0,0 +1,569 @@
+use crate::aof;
+use crate::command::{
+    FlushMode, InfoSection, MigrateCommandOption, RedisCommand, ReplconfCommand,
+    RestoreCommandOption, ScanCommandOption, SetCommandOption,
+};
+use crate::migrate;
+use crate::persistence;
+use crate::rdb;
+use crate::replication;
+use crate::resp::{bytes_to_string, string_to_bytes, RESP};
+use crate::server_state::{self, ServerState};
+use crate::store;
+use std::time::Duration;
+
+// 接続ごとの状態
+#[derive(Default)]
+pub struct Client {
+    pub db: usize,
+    // PSYNC を経てレプリカになった接続なら、そのレプリカの ID
+    pub replica_id: Option<u64>,
+    // この接続で最後に実行した書き込みを伝搬し終えた時点の master_repl_offset
+    pub last_write_offset: u64,
+    // PSYNC の前に REPLCONF で伝えられた、レプリカとしての情報
+    pub listening_port: Option<String>,
+    pub capa: Vec<String>,
+    // 実行したコマンドの代わりにレプリカと AOF へ伝搬するコマンド。空なら何も伝搬しない。
+    // MIGRATE は移行できたキーの DEL を自分で伝搬するので、空にする
+    pub propagate_as: Option<Vec<RedisCommand>>,
+}
+
+// 書き込みコマンドは実行後にレプリカへ伝搬する。読み取りコマンドはそのまま実行する
+pub fn handle_write_command(command: RedisCommand, client: &mut Client) -> Vec<RESP> {
+    // マスターから届くコマンドは replication が直接 handle_redis_command で適用するので、
+    // ここに来るのは通常のクライアントからのコマンドだけ
+    let state = ServerState::get();
+    let is_replica = matches!(state.role, server_state::Role::Slave { .. });
+    if is_replica {
+        if !state.replica_serve_stale_data
+            && !command.is_stale_ok()
+            && !replication::master_link_up()
+        {
+            return vec![RESP::simple_error(
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.",
+            )];
+        }
+        if state.replica_read_only && command.is_write() {
+            return vec![RESP::simple_error(
+                "READONLY You can't write against a read only replica.",
+            )];
+        }
+    }
+    // maxmemory を超えていればキーを追い出す。レプリカは自分では追い出さず、マスターから届く削除だけを反映する
+    // (replica-ignore-maxmemory)。マスターからのコマンドや AOF の読み込みはここを通らないので、
+    // メモリ不足で拒否されることはない
+    if !is_replica {
+        if let Err(e) = evict_if_needed() {
+            if command.is_denyoom() {
+                return vec![RESP::simple_error(&e.to_string())];
+            }
+        }
+    }
+    // MIGRATE は移行先とのやり取りの間も書き込みを止めないよう、ロックを取らずに実行する。
+    // 移行できたキーを消して DEL を伝搬するときだけ、その中でロックを取る
+    if !command.is_write() || matches!(command, RedisCommand::Migrate { .. }) {
+        return handle_redis_command(command, client);
+    }
+    let _guard = replication::command_write_lock();
+    let db = client.db;
+    let ret = handle_redis_command(command.clone(), client);
+    // replica-read-only no のレプリカへの書き込みは手元に適用するだけで伝搬しない。
+    // 伝搬するとオフセットがマスターより進み、サブレプリカへ流すストリームもマスターとずれる
+    if !is_replica {
+        for command in propagated_commands(command, &ret, client) {
+            client.last_write_offset = replication::propagate(db, command);
+        }
+    }
+    client.propagate_as = None;
+    ret
+}
+
+// 追い出したキーは DEL としてレプリカと AOF に伝える。
+// 追い出しと伝搬の間に他の書き込みが割り込まないよう、書き込みロックの中で行う
+fn evict_if_needed() -> Result<(), store::StoreError> {
+    if !store::over_maxmemory() {
+        return Ok(());
+    }
+    let _guard = replication::command_write_lock();
+    store::evict_if_needed(|db, key| {
+        let command = RedisCommand::Del {
+            keys: vec![key.to_string()],
+        };
+        if aof::enabled() {
+            aof::feed(db, command.clone());
+        }
+        replication::propagate(db, command);
+    })
+}
+
+// エラーになったコマンドは伝搬しない。ただし propagate_as があれば、エラーでもそれを伝搬する
+fn propagated_commands(command: RedisCommand, ret: &[RESP], client: &Client) -> Vec<RedisCommand> {
+    match &client.propagate_as {
+        Some(commands) => commands.clone(),
+        None if matches!(ret, [RESP::SimpleError(_)]) => vec![],
+        None => vec![command],
+    }
+}
+
+pub fn handle_redis_command(command: RedisCommand, client: &mut Client) -> Vec<RESP> {
+    // マスターから届いた書き込みもここを通るので、レプリカでも保存ルールや AOF の対象になる
+    let is_write = command.is_write();
+    let db = client.db;
+    let aof_command = (is_write && aof::enabled()).then(|| command.clone());
+    let ret = match command {
+        RedisCommand::Echo(s) => vec![RESP::bulk_strings(&s)],
+        RedisCommand::Ping => vec![RESP::simple_string("PONG")],
+        RedisCommand::Set {
+            key,
+            value,
+            options,
+        } => {
+            let expires_at = options
+                .iter()
+                .map(|option| match option {
+                    SetCommandOption::Px(px) => store::now() + px,
+                    SetCommandOption::Pxat(pxat) => *pxat,
+                })
+                .next();
+            store::set_with_expires_at(client.db, &key, &value, expires_at);
+            vec![RESP::simple_string("OK")]
+        }
+        RedisCommand::Get { key } => match store::get(client.db, &key) {
+            Ok(Some(value)) => vec![RESP::bulk_strings(&value)],
+            Ok(None) => vec![RESP::NullBulkStrings],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Info { section } => match section {
+            InfoSection::All => {
+                let info = [
+                    persistence::info(),
+                    handle_redis_command_info_replication(),
+                    handle_redis_command_info_memory(),
+                    handle_redis_command_info_stats(),
+                    handle_redis_command_info_keyspace(),
+                ];
+                vec![RESP::BulkStrings(info.join("\n\n"))]
+            }
+            InfoSection::Replication => {
+                vec![RESP::BulkStrings(handle_redis_command_info_replication())]
+            }
+            InfoSection::Keyspace => vec![RESP::BulkStrings(handle_redis_command_info_keyspace())],
+            InfoSection::Memory => vec![RESP::BulkStrings(handle_redis_command_info_memory())],
+            InfoSection::Stats => vec![RESP::BulkStrings(handle_redis_command_info_stats())],
+            InfoSection::Persistence => vec![RESP::BulkStrings(persistence::info())],
+        },
+        RedisCommand::Replconf {
+            command: ReplconfCommand::Ack(offset),
+        } => {
+            // ACK には応答しない
+            if let Some(id) = client.replica_id {
+                replication::record_ack(id, offset);
+            }
+            vec![]
+        }
+        // GETACK はマスターとの接続でのみ意味を持つ (replication::apply_master_stream で処理する)
+        RedisCommand::Replconf {
+            command: ReplconfCommand::GetAck(_),
+        } => vec![],
+        RedisCommand::Replconf {
+            command: ReplconfCommand::ListeningPort(port),
+        } => {
+            client.listening_port = Some(port);
+            vec![RESP::simple_string("OK")]
+        }
+        RedisCommand::Replconf {
+            command: ReplconfCommand::Capa(capa),
+        } => {
+            client.capa.extend(capa);
+            vec![RESP::simple_string("OK")]
+        }
+        RedisCommand::Role => handle_redis_command_role(),
+        RedisCommand::Wait {
+            numreplicas,
+            timeout,
+        } => {
+            // レプリカが GETACK を送るとマスターから受け取るストリームとオフセットがずれる
+            if ServerState::get().role != server_state::Role::Master {
+                return vec![RESP::simple_error(
+                    "ERR WAIT cannot be used with replica instances.",
+                )];
+            }
+            // timeout 0 は無期限に待つ
+            let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+            let acked = replication::wait(client.last_write_offset, numreplicas, timeout);
+            vec![RESP::Integer(acked as i64)]
+        }
+        RedisCommand::Replicaof { master } => match master {
+            Some((host, port)) => {
+                if replication::become_replica(host, port) {
+                    vec![RESP::simple_string("OK")]
+                } else {
+                    vec![RESP::simple_string(
+                        "OK Already connected to specified master",
+                    )]
+                }
+            }
+            None => {
+                replication::become_master();
+                vec![RESP::simple_string("OK")]
+            }
+        },
+        // 応答の後にバックログや RDB を生のまま送る必要があるので、接続側で replication::sync_replica を呼ぶ
+        RedisCommand::Psync { .. } => unreachable!("PSYNC is handled by the connection loop"),
+        RedisCommand::Del { keys } => vec![RESP::Integer(store::del(client.db, &keys) as i64)],
+        RedisCommand::Unlink { keys } => {
+            vec![RESP::Integer(store::unlink(client.db, &keys) as i64)]
+        }
+        RedisCommand::Exists { keys } => {
+            vec![RESP::Integer(store::exists(client.db, &keys) as i64)]
+        }
+        RedisCommand::Type { key } => {
+            vec![RESP::simple_string(
+                store::key_type(client.db, &key).unwrap_or("none"),
+            )]
+        }
+        RedisCommand::Rename { key, newkey } => match store::rename(client.db, &key, &newkey) {
+            Ok(()) => vec![RESP::simple_string("OK")],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Renamenx { key, newkey } => match store::renamenx(client.db, &key, &newkey) {
+            Ok(renamed) => vec![RESP::Integer(renamed as i64)],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Copy {
+            source,
+            destination,
+            db,
+            replace,
+        } => match store::copy(
+            client.db,
+            &source,
+            db.unwrap_or(client.db),
+            &destination,
+            replace,
+        ) {
+            Ok(copied) => vec![RESP::Integer(copied as i64)],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Dump { key } => match store::get_data(client.db, &key) {
+            Some((value, _)) => vec![RESP::BulkStrings(bytes_to_string(&rdb::dump_value(&value)))],
+            None => vec![RESP::NullBulkStrings],
+        },
+        RedisCommand::Restore {
+            key,
+            ttl,
+            payload,
+            options,
+        } => match handle_redis_command_restore(client.db, &key, ttl, &payload, &options) {
+            Ok(()) => vec![RESP::simple_string("OK")],
+            Err(e) => vec![RESP::simple_error(&e)],
+        },
+        RedisCommand::Migrate {
+            host,
+            port,
+            key,
+            destination_db,
+            timeout,
+            options,
+        } => match handle_redis_command_migrate(
+            client,
+            host,
+            port,
+            key,
+            destination_db,
+            timeout,
+            options,
+        ) {
+            Ok(reply) => vec![RESP::simple_string(reply)],
+            Err(e) => vec![RESP::simple_error(&e)],
+        },
+        RedisCommand::Touch { keys } => vec![RESP::Integer(store::touch(client.db, &keys) as i64)],
+        RedisCommand::Randomkey => match store::random_key(client.db) {
+            Some(key) => vec![RESP::bulk_strings(&key)],
+            None => vec![RESP::NullBulkStrings],
+        },
+        RedisCommand::Dbsize => vec![RESP::Integer(store::dbsize(client.db) as i64)],
+        RedisCommand::Keys { pattern } => vec![RESP::Array(
+            store::keys(client.db, &pattern)
+                .iter()
+                .map(|key| RESP::bulk_strings(key))
+                .collect(),
+        )],
+        RedisCommand::Scan { cursor, options } => {
+            let mut pattern = None;
+            let mut count = 10;
+            let mut type_name = None;
+            for option in &options {
+                match option {
+                    ScanCommandOption::Match(p) => pattern = Some(p.as_str()),
+                    ScanCommandOption::Count(c) => count = *c,
+                    ScanCommandOption::Type(t) => type_name = Some(t.as_str()),
+                }
+            }
+            let (next_cursor, keys) = store::scan(client.db, cursor, count, pattern, type_name);
+            vec![RESP::Array(vec![
+                RESP::BulkStrings(next_cursor.to_string()),
+                RESP::Array(keys.iter().map(|key| RESP::bulk_strings(key)).collect()),
+            ])]
+        }
+        RedisCommand::Select { index } => match store::check_db(index) {
+            Ok(()) => {
+                client.db = index;
+                vec![RESP::simple_string("OK")]
+            }
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Move { key, db } => match store::move_key(client.db, &key, db) {
+            Ok(moved) => vec![RESP::Integer(moved as i64)],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Swapdb { index1, index2 } => match store::swapdb(index1, index2) {
+            Ok(()) => vec![RESP::simple_string("OK")],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Flushdb { mode } => {
+            store::flushdb(client.db, mode == FlushMode::Async);
+            vec![RESP::simple_string("OK")]
+        }
+        RedisCommand::Flushall { mode } => {
+            store::flushall(mode == FlushMode::Async);
+            vec![RESP::simple_string("OK")]
+        }
+        RedisCommand::Save => match persistence::save() {
+            Ok(()) => vec![RESP::simple_string("OK")],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Bgsave => match persistence::bgsave() {
+            Ok(()) => vec![RESP::simple_string("Background saving started")],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+        RedisCommand::Lastsave => vec![RESP::Integer(persistence::lastsave() as i64)],
+        RedisCommand::Bgrewriteaof => match aof::rewrite() {
+            Ok(()) => vec![RESP::simple_string(
+                "Background append only file rewriting started",
+            )],
+            Err(e) => vec![RESP::simple_error(&e.to_string())],
+        },
+    };
+    if is_write {
+        let commands =
+            aof_command.map_or(vec![], |command| propagated_commands(command, &ret, client));
+        if client.propagate_as.is_some() || !matches!(ret.as_slice(), [RESP::SimpleError(_)]) {
+            persistence::add_dirty(1);
+        }
+        for command in commands {
+            aof::feed(db, command);
+        }
+    }
+    ret
+}
+
+// エラーは Redis と同じく、オプション、既存のキー、ペイロードの順に確かめる
+fn handle_redis_command_restore(
+    db: usize,
+    key: &str,
+    ttl: i64,
+    payload: &str,
+    options: &[RestoreCommandOption],
+) -> Result<(), String> {
+    let mut replace = false;
+    let mut absttl = false;
+    let mut access = None;
+    for option in options {
+        match *option {
+            RestoreCommandOption::Replace => replace = true,
+            RestoreCommandOption::Absttl => absttl = true,
+            // IDLETIME と FREQ は同時に指定できない
+            _ if access.is_some() => return Err("ERR syntax error".to_string()),
+            RestoreCommandOption::Idletime(seconds) if seconds < 0 => {
+                return Err("ERR Invalid IDLETIME value, must be >= 0".to_string())
+            }
+            RestoreCommandOption::Idletime(seconds) => {
+                access = Some(store::KeyAccess::IdleTime(seconds as u128 * 1000))
+            }
+            RestoreCommandOption::Freq(freq) => match u8::try_from(freq) {
+                Ok(freq) => access = Some(store::KeyAccess::Freq(freq)),
+                Err(_) => return Err("ERR Invalid FREQ value, must be >= 0 and <= 255".to_string()),
+            },
+        }
+    }
+    if ttl < 0 {
+        return Err("ERR Invalid TTL value, must be >= 0".to_string());
+    }
+    if !replace && store::exists(db, &[key.to_string()]) > 0 {
+        return Err(store::StoreError::BusyKey.to_string());
+    }
+    let value = rdb::restore_value(&string_to_bytes(payload)).map_err(|e| e.to_string())?;
+    let expires_at = match ttl {
+        0 => None,
+        ttl if absttl => Some(ttl as u128),
+        ttl => Some(store::now() + ttl as u128),
+    };
+    store::restore(db, key, value, expires_at, access);
+    Ok(())
+}
+
+fn handle_redis_command_migrate(
+    client: &mut Client,
+    host: String,
+    port: String,
+    key: String,
+    destination_db: usize,
+    timeout: i64,
+    options: Vec<MigrateCommandOption>,
+) -> Result<&'static str, String> {
+    let mut copy = false;
+    let mut replace = false;
+    let mut auth = None;
+    let mut keys = vec![key.clone()];
+    for option in options {
+        match option {
+            MigrateCommandOption::Copy => copy = true,
+            MigrateCommandOption::Replace => replace = true,
+            MigrateCommandOption::Auth(password) => auth = Some((None, password)),
+            MigrateCommandOption::Auth2(username, password) => {
+                auth = Some((Some(username), password))
+            }
+            MigrateCommandOption::Keys(_) if !key.is_empty() => {
+                return Err("ERR When using MIGRATE KEYS option, the key argument must be set to the empty string".to_string());
+            }
+            MigrateCommandOption::Keys(k) => keys = k,
+        }
+    }
+    // Redis と同じく、0 以下のタイムアウトは 1 秒として扱う
+    let timeout = if timeout <= 0 { 1000 } else { timeout as u64 };
+    let target = migrate::Target {
+        host,
+        port,
+        db: destination_db,
+        timeout: Duration::from_millis(timeout),
+        auth,
+    };
+    let (ret, migrated) = migrate::migrate(client.db, &keys, &target, replace);
+    // 移行先で RESTORE できたキーは、エラーになっても消して DEL として伝搬する
+    if !copy && !migrated.is_empty() {
+        delete_migrated(client, migrated);
+    }
+    // MIGRATE 自体は伝搬しない
+    client.propagate_as = Some(vec![]);
+    match ret {
+        Ok(migrate::MigrateReply::Ok) => Ok("OK"),
+        Ok(migrate::MigrateReply::NoKey) => Ok("NOKEY"),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// 消すことと伝搬することの間に他の書き込みが割り込まないよう、書き込みロックの中で行う
+fn delete_migrated(client: &mut Client, keys: Vec<String>) {
+    let is_replica = matches!(ServerState::get().role, server_state::Role::Slave { .. });
+    let command = RedisCommand::Del { keys };
+    let _guard = replication::command_write_lock();
+    handle_redis_command(command.clone(), client);
+    if !is_replica {
+        client.last_write_offset = replication::propagate(client.db, command);
+    }
+}
+
+fn handle_redis_command_info_replication() -> String {
+    let state = ServerState::get();
+    let mut ret = "# Replication\n".to_string();
+    ret.push_str(&match &state.role {
+        server_state::Role::Master => "role:master".to_string(),
+        server_state::Role::Slave {
+            master_host,
+            master_port,
+        } => {
+            let (link_up, last_io_seconds_ago, sync_in_progress) = replication::master_link_info();
+            format!(
+                "role:slave\nmaster_host:{}\nmaster_port:{}\nmaster_link_status:{}\nmaster_last_io_seconds_ago:{}\nmaster_sync_in_progress:{}\nslave_repl_offset:{}",
+                master_host,
+                master_port,
+                if link_up { "up" } else { "down" },
+                last_io_seconds_ago.map_or(-1, |seconds| seconds as i64),
+                sync_in_progress as u8,
+                state.master_repl_offset
+            )
+        }
+    });
+    let replicas = replication::replicas();
+    ret.push_str(&format!("\nconnected_slaves:{}", replicas.len()));
+    for (i, replica) in replicas.iter().enumerate() {
+        ret.push_str(&format!(
+            "\nslave{}:ip={},port={},state={},offset={},lag={}",
+            i, replica.ip, replica.port, replica.state, replica.offset, replica.lag
+        ));
+    }
+    let (backlog_active, backlog_size, backlog_first_byte_offset, backlog_histlen) =
+        replication::backlog_info();
+    ret.push_str(&format!(
+        "\nmaster_replid:{}\nmaster_replid2:{}\nmaster_repl_offset:{}\nsecond_repl_offset:{}\nrepl_backlog_active:{}\nrepl_backlog_size:{}\nrepl_backlog_first_byte_offset:{}\nrepl_backlog_histlen:{}",
+        state.master_replid,
+        state.master_replid2,
+        state.master_repl_offset,
+        state.second_repl_offset,
+        backlog_active as u8,
+        backlog_size,
+        backlog_first_byte_offset,
+        backlog_histlen
+    ));
+    ret
+}
+
+// マスター: ["master", offset, [[ip, port, offset], ...]]
+// レプリカ: ["slave", host, port, state, offset]
+fn handle_redis_command_role() -> Vec<RESP> {
+    let state = ServerState::get();
+    match state.role {
+        server_state::Role::Master => vec![RESP::Array(vec![
+            RESP::bulk_strings("master"),
+            RESP::Integer(state.master_repl_offset as i64),
+            RESP::Array(
+                replication::replicas()
+                    .into_iter()
+                    .map(|replica| {
+                        RESP::Array(vec![
+                            RESP::BulkStrings(replica.ip),
+                            RESP::BulkStrings(replica.port),
+                            RESP::BulkStrings(replica.offset.to_string()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ])],
+        server_state::Role::Slave {
+            master_host,
+            master_port,
+        } => vec![RESP::Array(vec![
+            RESP::bulk_strings("slave"),
+            RESP::BulkStrings(master_host),
+            RESP::Integer(master_port.parse().unwrap_or(0)),
+            RESP::bulk_strings(replication::master_link_state()),
+            RESP::Integer(state.master_repl_offset as i64),
+        ])],
+    }
+}
+
+fn handle_redis_command_info_memory() -> String {
+    let eviction = store::eviction_config();
+    format!(
+        "# Memory\nused_memory:{}\nmaxmemory:{}\nmaxmemory_policy:{}",
+        store::used_memory(),
+        eviction.maxmemory,
+        eviction.policy.as_str()
+    )
+}
+
+fn handle_redis_command_info_stats() -> String {
+    format!("# Stats\nevicted_keys:{}", store::evicted_keys())
+}
+
+fn handle_redis_command_info_keyspace() -> String {
+    let mut ret = "# Keyspace".to_string();
+    for (db, keys, expires) in store::keyspace() {
+        ret.push_str(&format!(
+            "\ndb{}:keys={},expires={},avg_ttl=0",
+            db, keys, expires
+        ));
+    }
+    ret
+}
@@ -0,0 +1,35 @@
+// Redis の intset。整数だけからなる小さなセットに使われる。
+// エンコーディング (要素のバイト数 u32)、要素数 u32 に続いて、昇順に並んだ整数が続く
+pub fn decode(input: &[u8]) -> Option<Vec<String>> {
+    let encoding = u32::from_le_bytes(input.get(0..4)?.try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(input.get(4..8)?.try_into().unwrap()) as usize;
+    if !matches!(encoding, 2 | 4 | 8) || input.len() != 8 + encoding * len {
+        return None;
+    }
+    let ret = input[8..]
+        .chunks(encoding)
+        .map(|b| match encoding {
+            2 => i16::from_le_bytes(b.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(b.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(b.try_into().unwrap()),
+        })
+        .map(|n| n.to_string())
+        .collect();
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // SADD s 1 2 -3 の intset
+        let data = b"\x02\x00\x00\x00\x03\x00\x00\x00\xfd\xff\x01\x00\x02\x00";
+        assert_eq!(
+            decode(data),
+            Some(vec!["-3".to_string(), "1".to_string(), "2".to_string()])
+        );
+        assert_eq!(decode(&data[..data.len() - 1]), None);
+    }
+}
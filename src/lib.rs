@@ -1,6 +1,21 @@
+pub mod aof;
 pub mod cli;
 pub mod command;
+pub mod crc64;
+pub mod data;
+pub mod glob;
+pub mod handler;
+pub mod intset;
+pub mod listpack;
+pub mod lzf;
+pub mod migrate;
 pub mod node;
+pub mod persistence;
+pub mod random;
+pub mod rdb;
+pub mod replication;
 pub mod resp;
 pub mod server_state;
 pub mod store;
+pub mod ziplist;
+pub mod zipmap;
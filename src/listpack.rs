@@ -0,0 +1,210 @@
+// Redis の listpack。RDB ではハッシュ・セット・ソート済みセット・リスト・ストリームの
+// 小さな値がこの形式の文字列として保存される。
+// ヘッダー (全体のバイト数 u32, 要素数 u16) に続いて各要素が並び、0xff で終わる。
+// 各要素はエンコーディング、データ、要素の長さ (backlen) からなる
+use crate::resp::{bytes_to_string, string_to_bytes};
+
+const HEADER_SIZE: usize = 6;
+const EOF: u8 = 0xff;
+
+// 整数は10進表記の文字列として返す
+pub fn decode(input: &[u8]) -> Option<Vec<String>> {
+    if input.len() < HEADER_SIZE + 1 {
+        return None;
+    }
+    let total = u32::from_le_bytes(input[0..4].try_into().unwrap()) as usize;
+    if total != input.len() || input[total - 1] != EOF {
+        return None;
+    }
+    let mut ret = vec![];
+    let mut pos = HEADER_SIZE;
+    while input[pos] != EOF {
+        let (element, len) = decode_element(&input[pos..total - 1])?;
+        ret.push(element);
+        pos += len + backlen_size(len);
+        if pos >= total {
+            return None;
+        }
+    }
+    // 要素数が 65535 以上のときは 65535 になっていて、数えないと分からない
+    let count = u16::from_le_bytes(input[4..6].try_into().unwrap());
+    if count != u16::MAX && count as usize != ret.len() {
+        return None;
+    }
+    Some(ret)
+}
+
+// 要素と、backlen を除いた要素のバイト数を返す
+fn decode_element(input: &[u8]) -> Option<(String, usize)> {
+    let first = *input.first()?;
+    let int = |n: i64, len: usize| Some((n.to_string(), len));
+    let bytes = |start: usize, len: usize| {
+        let data = input.get(start..start + len)?;
+        Some((bytes_to_string(data), start + len))
+    };
+    match first {
+        // 0xxxxxxx: 7ビットの符号なし整数
+        0x00..=0x7f => int(first as i64, 1),
+        // 10xxxxxx: 6ビットの長さの文字列
+        0x80..=0xbf => bytes(1, (first & 0x3f) as usize),
+        // 110xxxxx yyyyyyyy: 13ビットの符号付き整数
+        0xc0..=0xdf => {
+            let n = (((first & 0x1f) as i64) << 8) | *input.get(1)? as i64;
+            int(if n >= 1 << 12 { n - (1 << 13) } else { n }, 2)
+        }
+        // 1110xxxx yyyyyyyy: 12ビットの長さの文字列
+        0xe0..=0xef => {
+            let len = (((first & 0x0f) as usize) << 8) | *input.get(1)? as usize;
+            bytes(2, len)
+        }
+        0xf0 => {
+            let len = u32::from_le_bytes(input.get(1..5)?.try_into().unwrap());
+            bytes(5, len as usize)
+        }
+        0xf1 => int(
+            i16::from_le_bytes(input.get(1..3)?.try_into().unwrap()) as i64,
+            3,
+        ),
+        0xf2 => {
+            let b = input.get(1..4)?;
+            // 24ビットを上位に詰めてから算術シフトで符号を拡張する
+            let n = i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8;
+            int(n as i64, 4)
+        }
+        0xf3 => int(
+            i32::from_le_bytes(input.get(1..5)?.try_into().unwrap()) as i64,
+            5,
+        ),
+        0xf4 => int(i64::from_le_bytes(input.get(1..9)?.try_into().unwrap()), 9),
+        _ => None,
+    }
+}
+
+// backlen は要素の長さを 7 ビットずつ可変長で表したもの
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+fn encode_backlen(len: usize, out: &mut Vec<u8>) {
+    // 末尾から逆向きに読まれるので、上位の7ビットから書き、先頭以外のバイトは最上位ビットを立てる
+    let size = backlen_size(len);
+    for i in (0..size).rev() {
+        let byte = ((len >> (7 * i)) & 0x7f) as u8;
+        out.push(if i == size - 1 { byte } else { byte | 0x80 });
+    }
+}
+
+// Redis と同じく、整数として表せる文字列は整数のエンコーディングで書く
+pub fn encode(elements: &[String]) -> Vec<u8> {
+    let mut out = vec![0; HEADER_SIZE];
+    for element in elements {
+        let start = out.len();
+        match parse_int(element) {
+            Some(n) => encode_int(n, &mut out),
+            None => encode_string(&string_to_bytes(element), &mut out),
+        }
+        encode_backlen(out.len() - start, &mut out);
+    }
+    out.push(EOF);
+    let total = out.len() as u32;
+    out[0..4].copy_from_slice(&total.to_le_bytes());
+    let count = elements.len().min(u16::MAX as usize) as u16;
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+// 先頭の 0 や + を含まない、元の文字列に戻せる整数だけを整数として扱う
+fn parse_int(s: &str) -> Option<i64> {
+    let n = s.parse::<i64>().ok()?;
+    (n.to_string() == s).then_some(n)
+}
+
+fn encode_int(n: i64, out: &mut Vec<u8>) {
+    if (0..=127).contains(&n) {
+        out.push(n as u8);
+    } else if (-4096..=4095).contains(&n) {
+        let n = (n as u16) & 0x1fff;
+        out.push(0xc0 | (n >> 8) as u8);
+        out.push(n as u8);
+    } else if i16::try_from(n).is_ok() {
+        out.push(0xf1);
+        out.extend((n as i16).to_le_bytes());
+    } else if (-(1 << 23)..1 << 23).contains(&n) {
+        out.push(0xf2);
+        out.extend(&(n as i32).to_le_bytes()[..3]);
+    } else if i32::try_from(n).is_ok() {
+        out.push(0xf3);
+        out.extend((n as i32).to_le_bytes());
+    } else {
+        out.push(0xf4);
+        out.extend(n.to_le_bytes());
+    }
+}
+
+fn encode_string(bytes: &[u8], out: &mut Vec<u8>) {
+    let len = bytes.len();
+    if len < 1 << 6 {
+        out.push(0x80 | len as u8);
+    } else if len < 1 << 12 {
+        out.push(0xe0 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else {
+        out.push(0xf0);
+        out.extend((len as u32).to_le_bytes());
+    }
+    out.extend(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // HSET h a 1 b hello の listpack。1 は整数として格納される
+        let data = b"\x16\x00\x00\x00\x04\x00\x81a\x02\x01\x01\x81b\x02\x85hello\x06\xff";
+        assert_eq!(
+            decode(data),
+            Some(vec![
+                "a".to_string(),
+                "1".to_string(),
+                "b".to_string(),
+                "hello".to_string()
+            ])
+        );
+        assert_eq!(decode(&data[..data.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let elements = [
+            "a",
+            "1",
+            "-1",
+            "4095",
+            "-4096",
+            "30000",
+            "-8388608",
+            "100000000",
+            "1099511627776",
+            "007",
+            "",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .chain(["x".repeat(100), "y".repeat(5000)])
+        .collect::<Vec<_>>();
+        let data = encode(&elements);
+        assert_eq!(decode(&data), Some(elements));
+        assert_eq!(
+            encode(&["a".to_string(), "1".to_string()]),
+            b"\x0c\x00\x00\x00\x02\x00\x81a\x02\x01\x01\xff"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// RDB の圧縮文字列で使われる LZF の展開。
+// 制御バイトの上位3ビットが 0 なら (下位5ビット + 1) バイトのリテラル、
+// それ以外は既に展開したデータからの後方参照 (長さと距離)
+pub fn decompress(input: &[u8], out_len: usize) -> Option<Vec<u8>> {
+    // out_len はファイルに書かれた値なので信用しない。1バイトの入力は高々 88 バイトにしか展開されない
+    let mut out = Vec::with_capacity(out_len.min(input.len() * 88));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 1 << 5 {
+            let len = ctrl + 1;
+            out.extend_from_slice(input.get(i..i + len)?);
+            i += len;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i)? as usize;
+                i += 1;
+            }
+            let distance = ((ctrl & 0x1f) << 8) + *input.get(i)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(distance)?;
+            // 参照元と展開先が重なることがあるので1バイトずつコピーする
+            for k in 0..len + 2 {
+                out.push(out[start + k]);
+            }
+        }
+        if out.len() > out_len {
+            return None;
+        }
+    }
+    (out.len() == out_len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        // "abc" のリテラルと、3バイト前からの6バイトの参照
+        assert_eq!(
+            decompress(b"\x02abc\x80\x02", 9),
+            Some(b"abcabcabc".to_vec())
+        );
+        // 長さ 7 以上の参照は長さが次のバイトに続く
+        assert_eq!(decompress(b"\x00a\xe0\x03\x00", 13), Some(b"a".repeat(13)));
+        assert_eq!(decompress(b"\x02abc\x80\x05", 9), None);
+        assert_eq!(decompress(b"\x02ab", 3), None);
+        // 巨大な長さが書かれていても、その分を確保せずに長さの違いで失敗する
+        assert_eq!(decompress(b"\x00a", 1 << 46), None);
+    }
+}
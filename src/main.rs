@@ -1,9 +1,12 @@
-use redis_starter_rust::command::{InfoSection, RedisCommand, ReplconfCommand, SetCommandOption};
+use redis_starter_rust::aof::{self, AofConfig};
+use redis_starter_rust::command::RedisCommand;
+use redis_starter_rust::handler::{handle_write_command, Client};
 use redis_starter_rust::resp::RESP;
 use redis_starter_rust::server_state::{Role, ServerState};
-use redis_starter_rust::{server_state, store};
+use redis_starter_rust::{migrate, persistence, rdb, replication, store};
 use std::net::TcpStream;
 use std::thread;
+use std::time::Duration;
 use std::{
     io::{Read, Write},
     net::TcpListener,
@@ -11,24 +14,120 @@ use std::{
 
 const DEFAULT_PORT: &str = "6379";
 const DEFAULT_HOST: &str = "127.0.0.1";
-const EMPTY_RDB_FILE: &[u8] = &[
-    0x52, 0x45, 0x44, 0x49, 0x53, 0x30, 0x30, 0x31, 0x31, 0xfa, 0x09, 0x72, 0x65, 0x64, 0x69, 0x73,
-    0x2d, 0x76, 0x65, 0x72, 0x05, 0x37, 0x2e, 0x32, 0x2e, 0x30, 0xfa, 0x0a, 0x72, 0x65, 0x64, 0x69,
-    0x73, 0x2d, 0x62, 0x69, 0x74, 0x73, 0xc0, 0x40, 0xfa, 0x05, 0x63, 0x74, 0x69, 0x6d, 0x65, 0xc2,
-    0x6d, 0x08, 0xbc, 0x65, 0xfa, 0x08, 0x75, 0x73, 0x65, 0x64, 0x2d, 0x6d, 0x65, 0x6d, 0xc2, 0xb0,
-    0xc4, 0x10, 0x00, 0xfa, 0x08, 0x61, 0x6f, 0x66, 0x2d, 0x62, 0x61, 0x73, 0x65, 0xc0, 0x00, 0xff,
-    0xf0, 0x6e, 0x3b, 0xfe, 0xc0, 0xff, 0x5a, 0xa2,
-];
+const DEFAULT_REPL_PING_REPLICA_PERIOD: u64 = 10; // seconds
+const DEFAULT_REPL_DISKLESS_SYNC_DELAY: u64 = 5; // seconds
 
 fn main() {
     let args = redis_starter_rust::cli::CliArgs::parse();
     ServerState::init(&args.role);
-    handshake(args.role, args.port.as_deref().unwrap_or(DEFAULT_PORT));
+    ServerState::update(|state| {
+        state.replica_read_only = args.replica_read_only.unwrap_or(true);
+        state.replica_serve_stale_data = args.replica_serve_stale_data.unwrap_or(true);
+        if let Some(dir) = args.dir.clone() {
+            state.dir = dir;
+        }
+        if let Some(dbfilename) = args.dbfilename.clone() {
+            state.dbfilename = dbfilename;
+        }
+        if let Some(appendfilename) = args.appendfilename.clone() {
+            state.appendfilename = appendfilename;
+        }
+        if let Some(appenddirname) = args.appenddirname.clone() {
+            state.appenddirname = appenddirname;
+        }
+    });
+    store::init(args.databases.unwrap_or(store::DEFAULT_DATABASES));
+    // 壊れたファイルのまま起動するとデータを失うので、読めなければ起動しない。
+    // AOF が有効なら、RDB より新しい書き込みまで含む AOF を優先する
+    let appendonly = args.appendonly.unwrap_or(false);
+    let state = ServerState::get();
+    let aof_dir = state.aof_dir();
+    let default_aof = AofConfig::default();
+    aof::configure(
+        &aof_dir,
+        &state.appendfilename,
+        AofConfig {
+            fsync: args.appendfsync.unwrap_or(default_aof.fsync),
+            load_truncated: args
+                .aof_load_truncated
+                .unwrap_or(default_aof.load_truncated),
+            use_rdb_preamble: args
+                .aof_use_rdb_preamble
+                .unwrap_or(default_aof.use_rdb_preamble),
+            auto_rewrite_percentage: args
+                .auto_aof_rewrite_percentage
+                .unwrap_or(default_aof.auto_rewrite_percentage),
+            auto_rewrite_min_size: args
+                .auto_aof_rewrite_min_size
+                .unwrap_or(default_aof.auto_rewrite_min_size),
+        },
+    );
+    let aof_loaded = if appendonly { aof::load() } else { Ok(false) };
+    let (path, loaded) = match aof_loaded {
+        Ok(false) => {
+            let rdb_path = state.rdb_path();
+            (rdb_path.clone(), rdb::load_file(&rdb_path))
+        }
+        loaded => (aof_dir.clone(), loaded),
+    };
+    match loaded {
+        Ok(true) => println!("loaded {}", path.display()),
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("failed to load {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+    persistence::clear_dirty();
+    if appendonly {
+        if let Err(e) = aof::start() {
+            eprintln!("failed to open {}: {}", aof_dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    persistence::set_save_params(
+        args.save
+            .unwrap_or(persistence::DEFAULT_SAVE_PARAMS.to_vec()),
+    );
+    persistence::start_cron();
+    migrate::start_cron();
+    let default_eviction = store::EvictionConfig::default();
+    store::configure_eviction(store::EvictionConfig {
+        maxmemory: args.maxmemory.unwrap_or(default_eviction.maxmemory),
+        policy: args.maxmemory_policy.unwrap_or(default_eviction.policy),
+        samples: args.maxmemory_samples.unwrap_or(default_eviction.samples),
+    });
+    if let Some(size) = args.repl_backlog_size {
+        replication::set_backlog_size(size);
+    }
+    if let Some(timeout) = args.repl_timeout {
+        replication::set_repl_timeout(Duration::from_secs(timeout));
+    }
+    replication::configure_diskless_sync(
+        args.repl_diskless_sync.unwrap_or(false),
+        Duration::from_secs(
+            args.repl_diskless_sync_delay
+                .unwrap_or(DEFAULT_REPL_DISKLESS_SYNC_DELAY),
+        ),
+    );
+    replication::start_pinger(Duration::from_secs(
+        args.repl_ping_replica_period
+            .unwrap_or(DEFAULT_REPL_PING_REPLICA_PERIOD),
+    ));
+    let port = args.port.unwrap_or(DEFAULT_PORT.to_string());
+    replication::set_listening_port(&port);
+    if let Role::Slave {
+        master_host,
+        master_port,
+    } = args.role
+    {
+        replication::start_replica(master_host, master_port, None);
+    }
 
     let listener = TcpListener::bind(format!(
         "{}:{}",
         args.host.unwrap_or(DEFAULT_HOST.to_string()),
-        args.port.unwrap_or(DEFAULT_PORT.to_string())
+        port
     ))
     .unwrap();
 
@@ -44,116 +143,41 @@ fn main() {
     }
 }
 
-fn handshake(role: Role, port: &str) {
-    match role {
-        Role::Master => {}
-        Role::Slave {
-            master_host,
-            master_port,
-        } => {
-            let stream = TcpStream::connect(format!("{}:{}", master_host, master_port)).unwrap();
-            let mut node = redis_starter_rust::node::Node::new(stream);
-            node.write(RedisCommand::Ping.to_resp());
-            let _ = node.read();
-
-            node.write(
-                RedisCommand::Replconf {
-                    command: ReplconfCommand::ListeningPort(port.to_string()),
-                }
-                .to_resp(),
-            );
-            let _ = node.read();
-
-            node.write(
-                RedisCommand::Replconf {
-                    command: ReplconfCommand::Capa("psync2".to_string()),
-                }
-                .to_resp(),
-            );
-            let _ = node.read();
-
-            node.write(
-                RedisCommand::Psync {
-                    master_replid: "?".to_string(),
-                    master_repl_offset: -1,
-                }
-                .to_resp(),
-            );
-        }
-    }
-}
-
 fn handle_stream(mut stream: TcpStream) {
     println!("accepted new connection");
+    let mut client = Client::default();
+    // 1回の read に複数のコマンドや途中までのコマンドが含まれることがあるので、溜めてからパースする
+    let mut buf = vec![];
     loop {
-        let mut buf = [0; 1024];
-        let read_count = stream.read(&mut buf).unwrap();
+        let mut read_buf = [0; 4096];
+        let read_count = stream.read(&mut read_buf).unwrap();
         if read_count == 0 {
             println!("connection closed");
             break;
-        } else {
-            let got = RESP::from_bytes(&buf[..read_count]);
+        }
+        buf.extend_from_slice(&read_buf[..read_count]);
+        while let Some((got, size)) = RESP::parse(&buf) {
+            buf.drain(..size);
             println!("got: {:?}", got.clone());
-            let ret = handle_redis_command(RedisCommand::new(got));
+            let command = RedisCommand::new(got);
+            if let RedisCommand::Psync {
+                master_replid,
+                master_repl_offset,
+            } = command
+            {
+                client.replica_id = replication::sync_replica(
+                    stream.try_clone().unwrap(),
+                    &client,
+                    &master_replid,
+                    master_repl_offset,
+                );
+                continue;
+            }
+            let ret = handle_write_command(command, &mut client);
             for resp in ret {
                 println!("send: {:?}", resp.clone());
-                stream.write(&resp.as_bytes()).unwrap();
+                stream.write_all(&resp.as_bytes()).unwrap();
             }
         }
     }
 }
-
-fn handle_redis_command(command: RedisCommand) -> Vec<RESP> {
-    match command {
-        RedisCommand::Echo(s) => vec![RESP::bulk_strings(&s)],
-        RedisCommand::Ping => vec![RESP::simple_string("PONG")],
-        RedisCommand::Set {
-            key,
-            value,
-            options,
-        } => {
-            let px = options.iter().find_map(|option| match option {
-                SetCommandOption::Px(px) => Some(*px),
-            });
-            store::set(&key, &value, px);
-            vec![RESP::simple_string("OK")]
-        }
-        RedisCommand::Get { key } => match store::get(&key) {
-            Some(value) => vec![RESP::bulk_strings(&value)],
-            None => vec![RESP::NullBulkStrings],
-        },
-        RedisCommand::Info { section } => match section {
-            InfoSection::All => handle_redis_command_info_replication(),
-            InfoSection::Replication => handle_redis_command_info_replication(),
-        },
-        RedisCommand::Replconf { .. } => {
-            // TODO: implement
-            vec![RESP::simple_string("OK")]
-        }
-        RedisCommand::Psync { .. } => {
-            let state = ServerState::get();
-            vec![
-                RESP::SimpleString(format!(
-                    "FULLRESYNC {} {}\n",
-                    state.master_replid, state.master_repl_offset
-                )),
-                RESP::Rdb(EMPTY_RDB_FILE.to_vec()),
-            ]
-        }
-    }
-}
-
-fn handle_redis_command_info_replication() -> Vec<RESP> {
-    let state = ServerState::get();
-    let ret = match state.role {
-        server_state::Role::Master => RESP::BulkStrings(format!(
-            "role:master\nmaster_replid:{}\nmaster_repl_offset:{}",
-            state.master_replid, state.master_repl_offset
-        )),
-        server_state::Role::Slave {
-            master_host: _,
-            master_port: _,
-        } => RESP::bulk_strings("role:slave"),
-    };
-    vec![ret]
-}
@@ -0,0 +1,262 @@
+use crate::command::{RedisCommand, RestoreCommandOption};
+use crate::node::Node;
+use crate::rdb;
+use crate::resp::{bytes_to_string, RESP};
+use crate::store;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// 同じ移行先への MIGRATE を続けて実行するときのために、接続をしばらく使い回す
+const MIGRATE_SOCKET_CACHE_ITEMS: usize = 64;
+const MIGRATE_SOCKET_CACHE_TTL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    // キーは "host:port"
+    static ref SOCKETS: Mutex<HashMap<String, CachedSocket>> = Mutex::new(HashMap::new());
+}
+
+struct CachedSocket {
+    node: Node,
+    // 最後に SELECT した DB。SELECT に失敗したら None に戻す
+    last_db: Option<usize>,
+    last_use: Instant,
+}
+
+#[derive(Debug, Error)]
+pub enum MigrateError {
+    #[error("IOERR error or timeout connecting to the client")]
+    Connect,
+    #[error("IOERR error or timeout writing to target instance")]
+    Write,
+    #[error("IOERR error or timeout reading to target instance")]
+    Read,
+    #[error("ERR Target instance replied with error: {0}")]
+    Target(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MigrateReply {
+    Ok,
+    // 移行するキーが1つもなかった
+    NoKey,
+}
+
+pub struct Target {
+    pub host: String,
+    pub port: String,
+    pub db: usize,
+    pub timeout: Duration,
+    // AUTH (ユーザー名, パスワード)。ユーザー名が None なら AUTH password
+    pub auth: Option<(Option<String>, String)>,
+}
+
+// keys を移行先に RESTORE する。2つ目の値は移行先で RESTORE できたキーで、
+// エラーになっても一部のキーは移行できていることがある。
+// キーの削除は呼び出し側が行う
+pub fn migrate(
+    db: usize,
+    keys: &[String],
+    target: &Target,
+    replace: bool,
+) -> (Result<MigrateReply, MigrateError>, Vec<String>) {
+    let values = keys
+        .iter()
+        .filter_map(|key| store::get_data(db, key).map(|value| (key, value)))
+        .collect::<Vec<_>>();
+    if values.is_empty() {
+        return (Ok(MigrateReply::NoKey), vec![]);
+    }
+    let commands = values
+        .into_iter()
+        .map(|(key, (value, expires_at))| {
+            // 移行先の時計に依らないよう、残り時間で渡す
+            let ttl = expires_at.map_or(0, |expires_at| {
+                expires_at.saturating_sub(store::now()).max(1) as i64
+            });
+            let options = if replace {
+                vec![RestoreCommandOption::Replace]
+            } else {
+                vec![]
+            };
+            RedisCommand::Restore {
+                key: key.clone(),
+                ttl,
+                payload: bytes_to_string(&rdb::dump_value(&value)),
+                options,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let address = format!("{}:{}", target.host, target.port);
+    let mut may_retry = true;
+    loop {
+        let (mut socket, cached) = match take_socket(&address, target.timeout) {
+            Ok(socket) => socket,
+            Err(_) => return (Err(MigrateError::Connect), vec![]),
+        };
+        let sent = send(&mut socket, target, &commands);
+        // キャッシュしていた接続が切れていただけなら、つなぎ直して1回だけやり直す
+        if sent.retryable && cached && may_retry {
+            may_retry = false;
+            continue;
+        }
+        if !matches!(sent.ret, Err(MigrateError::Write | MigrateError::Read)) {
+            socket.last_use = Instant::now();
+            put_socket(address, socket);
+        }
+        return (sent.ret, sent.migrated);
+    }
+}
+
+struct Sent {
+    ret: Result<MigrateReply, MigrateError>,
+    // 移行先で RESTORE できたキー
+    migrated: Vec<String>,
+    // 移行先でまだ何も実行されていないと分かっている接続のエラー。タイムアウトは含まない
+    retryable: bool,
+}
+
+// AUTH、SELECT、RESTORE をまとめて送ってから、まとめて返事を読む。
+// 接続のエラーでも、それまでに返事を読めたキーは移行できている
+fn send(socket: &mut CachedSocket, target: &Target, commands: &[RedisCommand]) -> Sent {
+    let failed = |ret, migrated, e: Option<io::Error>| Sent {
+        ret: Err(ret),
+        migrated,
+        retryable: e.is_some_and(|e| {
+            !matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            )
+        }),
+    };
+    let timeout = Some(target.timeout);
+    let node = &mut socket.node;
+    if let Err(e) = node
+        .set_read_timeout(timeout)
+        .and_then(|()| node.set_write_timeout(timeout))
+    {
+        return failed(MigrateError::Write, vec![], Some(e));
+    }
+
+    let mut requests = vec![];
+    if let Some((username, password)) = &target.auth {
+        let mut args = vec!["AUTH".to_string()];
+        args.extend(username.clone());
+        args.push(password.clone());
+        requests.push(RESP::Array(
+            args.into_iter().map(RESP::BulkStrings).collect(),
+        ));
+    }
+    let select = socket.last_db != Some(target.db);
+    if select {
+        requests.push(RedisCommand::Select { index: target.db }.to_resp());
+    }
+    let preamble = requests.len();
+    requests.extend(commands.iter().map(|command| command.clone().to_resp()));
+    for request in requests {
+        if let Err(e) = node.write(request) {
+            return failed(MigrateError::Write, vec![], Some(e));
+        }
+    }
+
+    // AUTH や SELECT に失敗していたら、RESTORE が成功していても移行先の DB は分からない
+    let mut error = None;
+    for _ in 0..preamble {
+        match node.read() {
+            Ok(RESP::SimpleError(e)) => {
+                error.get_or_insert(e);
+            }
+            Ok(_) => {}
+            Err(e) => return failed(MigrateError::Read, vec![], Some(e)),
+        }
+    }
+    let preamble_failed = error.is_some();
+    let mut migrated = vec![];
+    for (i, command) in commands.iter().enumerate() {
+        match node.read() {
+            Ok(RESP::SimpleError(e)) => {
+                error.get_or_insert(e);
+            }
+            Ok(_) if preamble_failed => {}
+            Ok(_) => {
+                if let RedisCommand::Restore { key, .. } = command {
+                    migrated.push(key.clone());
+                }
+            }
+            // 最初の返事も読めていなければ、やり直してよい
+            Err(e) => {
+                let e = (i == 0 && error.is_none()).then_some(e);
+                return failed(MigrateError::Read, migrated, e);
+            }
+        }
+    }
+    match error {
+        Some(e) => {
+            // 失敗したら、次は必ず SELECT する
+            socket.last_db = None;
+            failed(MigrateError::Target(e), migrated, None)
+        }
+        None => {
+            socket.last_db = Some(target.db);
+            Sent {
+                ret: Ok(MigrateReply::Ok),
+                migrated,
+                retryable: false,
+            }
+        }
+    }
+}
+
+// キャッシュした接続があれば取り出し、なければ新しく接続する。2つ目の値はキャッシュから取り出したか
+fn take_socket(address: &str, timeout: Duration) -> io::Result<(CachedSocket, bool)> {
+    if let Some(socket) = SOCKETS.lock().unwrap().remove(address) {
+        return Ok((socket, true));
+    }
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                let socket = CachedSocket {
+                    node: Node::new(stream),
+                    last_db: None,
+                    last_use: Instant::now(),
+                };
+                return Ok((socket, false));
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn put_socket(address: String, socket: CachedSocket) {
+    let mut sockets = SOCKETS.lock().unwrap();
+    // いっぱいなら、一番長く使っていない接続を閉じる
+    if sockets.len() >= MIGRATE_SOCKET_CACHE_ITEMS {
+        if let Some(oldest) = sockets
+            .iter()
+            .min_by_key(|(_, socket)| socket.last_use)
+            .map(|(address, _)| address.clone())
+        {
+            sockets.remove(&oldest);
+        }
+    }
+    sockets.insert(address, socket);
+}
+
+// MIGRATE_SOCKET_CACHE_TTL より長く使っていない接続を1秒ごとに閉じる
+pub fn start_cron() {
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(1));
+        SOCKETS
+            .lock()
+            .unwrap()
+            .retain(|_, socket| socket.last_use.elapsed() <= MIGRATE_SOCKET_CACHE_TTL);
+    });
+}
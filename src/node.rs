@@ -1,26 +1,74 @@
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::TcpStream,
+    time::Duration,
 };
 
 use crate::resp::RESP;
 
 pub struct Node {
     stream: TcpStream,
+    // 受信済みでまだパースしていないデータ
+    buf: Vec<u8>,
 }
 
 impl Node {
     pub fn new(stream: TcpStream) -> Self {
-        Node { stream }
+        Node {
+            stream,
+            buf: vec![],
+        }
     }
 
-    pub fn write(&mut self, resp: RESP) {
-        self.stream.write(resp.to_string().as_bytes()).unwrap();
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
     }
 
-    pub fn read(&mut self) -> RESP {
-        let mut buf: [u8; 1024] = [0; 1024];
-        self.stream.read(&mut buf).unwrap();
-        RESP::from_bytes(&buf)
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    pub fn write(&mut self, resp: RESP) -> io::Result<()> {
+        self.stream.write_all(&resp.as_bytes())
+    }
+
+    pub fn read(&mut self) -> io::Result<RESP> {
+        Ok(self.read_raw()?.0)
+    }
+
+    // 受け取ったバイト列もそのまま返す。レプリケーションのオフセット計算や、
+    // サブレプリカへの中継に使う
+    pub fn read_raw(&mut self) -> io::Result<(RESP, Vec<u8>)> {
+        self.read_by(RESP::parse)
+    }
+
+    pub fn read_rdb(&mut self) -> io::Result<Vec<u8>> {
+        // 大きな RDB を少しずつ受信しても、ディスクレス同期の目印は受信した分を一度ずつしか探さない
+        let mut searched = 0;
+        match self.read_by(|data| RESP::parse_rdb(data, &mut searched))?.0 {
+            RESP::Rdb(data) => Ok(data),
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_by(
+        &mut self,
+        mut parse: impl FnMut(&[u8]) -> Option<(RESP, usize)>,
+    ) -> io::Result<(RESP, Vec<u8>)> {
+        loop {
+            if let Some((resp, size)) = parse(&self.buf) {
+                let raw = self.buf.drain(..size).collect();
+                return Ok((resp, raw));
+            }
+            let mut buf = [0; 4096];
+            let read_count = self.stream.read(&mut buf)?;
+            if read_count == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed",
+                ));
+            }
+            self.buf.extend_from_slice(&buf[..read_count]);
+        }
     }
 }
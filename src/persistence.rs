@@ -0,0 +1,179 @@
+use crate::aof;
+use crate::rdb;
+use crate::server_state::ServerState;
+use crate::store;
+use lazy_static::lazy_static;
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// save ルールを確認する間隔
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+// BGSAVE が失敗したら、save ルールによる次の試行までこれだけ待つ (秒)
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+// Redis と同じ既定の save ルール
+pub const DEFAULT_SAVE_PARAMS: [SaveParam; 3] = [
+    SaveParam {
+        seconds: 3600,
+        changes: 1,
+    },
+    SaveParam {
+        seconds: 300,
+        changes: 100,
+    },
+    SaveParam {
+        seconds: 60,
+        changes: 10000,
+    },
+];
+
+lazy_static! {
+    static ref PERSISTENCE: Mutex<Persistence> = Mutex::new(Persistence {
+        dirty: 0,
+        dirty_before_bgsave: 0,
+        last_save: unix_time(),
+        last_bgsave_try: 0,
+        last_bgsave_ok: true,
+        bgsave_in_progress: false,
+        save_params: vec![],
+    });
+}
+
+struct Persistence {
+    // 最後に保存してから実行された書き込みの数
+    dirty: u64,
+    // 実行中の BGSAVE を始めた時点の dirty。成功したらこの分だけ減らす
+    dirty_before_bgsave: u64,
+    // 以下の時刻は UNIX 時間 (秒)
+    last_save: u64,
+    last_bgsave_try: u64,
+    last_bgsave_ok: bool,
+    bgsave_in_progress: bool,
+    save_params: Vec<SaveParam>,
+}
+
+// save <seconds> <changes>: seconds 秒以上経っていて changes 回以上書き込まれていれば BGSAVE する
+#[derive(Debug, PartialEq, Clone)]
+pub struct SaveParam {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum PersistenceError {
+    #[error("ERR Background save already in progress")]
+    BgsaveInProgress,
+    #[error("ERR {0}")]
+    Io(#[from] io::Error),
+}
+
+pub fn set_save_params(save_params: Vec<SaveParam>) {
+    PERSISTENCE.lock().unwrap().save_params = save_params;
+}
+
+// 起動時の読み込みで実行したコマンドは数えない
+pub fn clear_dirty() {
+    PERSISTENCE.lock().unwrap().dirty = 0;
+}
+
+pub fn add_dirty(n: u64) {
+    PERSISTENCE.lock().unwrap().dirty += n;
+}
+
+// SAVE: 書き出し終わるまで待つ
+pub fn save() -> Result<(), PersistenceError> {
+    let dirty = {
+        let persistence = PERSISTENCE.lock().unwrap();
+        if persistence.bgsave_in_progress {
+            return Err(PersistenceError::BgsaveInProgress);
+        }
+        persistence.dirty
+    };
+    rdb::write_file(&ServerState::get().rdb_path(), &rdb::dump(&[]))?;
+    let mut persistence = PERSISTENCE.lock().unwrap();
+    persistence.dirty = persistence.dirty.saturating_sub(dirty);
+    persistence.last_save = unix_time();
+    Ok(())
+}
+
+// BGSAVE: その時点のスナップショットを取り、書き出しは別スレッドで行う。
+// スナップショットはデータを共有するだけなので、取る間もクライアントはほとんど止まらない
+pub fn bgsave() -> Result<(), PersistenceError> {
+    {
+        let mut persistence = PERSISTENCE.lock().unwrap();
+        if persistence.bgsave_in_progress {
+            return Err(PersistenceError::BgsaveInProgress);
+        }
+        persistence.bgsave_in_progress = true;
+        persistence.dirty_before_bgsave = persistence.dirty;
+        persistence.last_bgsave_try = unix_time();
+    }
+    let snapshot = store::snapshot();
+    let path = ServerState::get().rdb_path();
+    thread::spawn(move || {
+        let ret = rdb::write_file(&path, &rdb::encode(&[], &snapshot.entries()));
+        let mut persistence = PERSISTENCE.lock().unwrap();
+        persistence.bgsave_in_progress = false;
+        persistence.last_bgsave_ok = ret.is_ok();
+        match ret {
+            Ok(()) => {
+                persistence.dirty = persistence
+                    .dirty
+                    .saturating_sub(persistence.dirty_before_bgsave);
+                persistence.last_save = unix_time();
+            }
+            Err(e) => println!("background save error: {}", e),
+        }
+    });
+    Ok(())
+}
+
+pub fn lastsave() -> u64 {
+    PERSISTENCE.lock().unwrap().last_save
+}
+
+// save ルールを満たしたら BGSAVE する
+pub fn start_cron() {
+    thread::spawn(|| loop {
+        thread::sleep(CRON_INTERVAL);
+        let should_save = {
+            let persistence = PERSISTENCE.lock().unwrap();
+            let now = unix_time();
+            // 失敗した直後は、少し間を空けてから再試行する
+            let can_retry = persistence.last_bgsave_ok
+                || now - persistence.last_bgsave_try > BGSAVE_RETRY_DELAY;
+            !persistence.bgsave_in_progress
+                && can_retry
+                && persistence.save_params.iter().any(|param| {
+                    persistence.dirty >= param.changes
+                        && now - persistence.last_save >= param.seconds
+                })
+        };
+        if should_save {
+            let _ = bgsave();
+        }
+        aof::rewrite_if_needed();
+    });
+}
+
+pub fn info() -> String {
+    let persistence = PERSISTENCE.lock().unwrap();
+    format!(
+        "# Persistence\nloading:0\nrdb_changes_since_last_save:{}\nrdb_bgsave_in_progress:{}\nrdb_last_save_time:{}\nrdb_last_bgsave_status:{}\n{}",
+        persistence.dirty,
+        persistence.bgsave_in_progress as u8,
+        persistence.last_save,
+        if persistence.last_bgsave_ok { "ok" } else { "err" },
+        aof::info()
+    )
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,26 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// std の RandomState は生成ごとに異なる鍵を持つので、それを乱数源として使う
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+pub fn random_index(n: usize) -> usize {
+    (random_u64() % n as u64) as usize
+}
+
+// replid のような 16 進文字列
+pub fn random_hex(len: usize) -> String {
+    let mut ret = String::new();
+    while ret.len() < len {
+        ret.push_str(&format!("{:016x}", random_u64()));
+    }
+    ret.truncate(len);
+    ret
+}
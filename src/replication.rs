@@ -0,0 +1,1201 @@
+use crate::aof;
+use crate::command::{RedisCommand, ReplconfCommand};
+use crate::handler::{self, Client};
+use crate::node::Node;
+use crate::random;
+use crate::rdb;
+use crate::resp::RESP;
+use crate::server_state::{Role, ServerState};
+use crate::store;
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+const DEFAULT_DISKLESS_SYNC_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_REPL_TIMEOUT: Duration = Duration::from_secs(60);
+// 再接続の間隔。失敗するたびに倍にし、同期に成功したら戻す
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(10);
+const REPLCONF_ACK_PERIOD: Duration = Duration::from_secs(1);
+// ディスクレス同期で、RDB をこの大きさずつ作ってはレプリカに渡す
+const DISKLESS_SYNC_CHUNK_SIZE: usize = 64 * 1024;
+// 各レプリカの書き込みスレッドが送り終えていない塊がこれだけ溜まったら、RDB を作るのを待つ
+const DISKLESS_SYNC_MAX_PENDING_CHUNKS: usize = 16;
+
+lazy_static! {
+    static ref REPLICAS: Mutex<Replicas> = Mutex::new(Replicas {
+        next_id: 0,
+        replicas: vec![],
+        selected_db: None,
+        backlog: None,
+        backlog_size: DEFAULT_BACKLOG_SIZE,
+        diskless_sync: false,
+        diskless_sync_delay: DEFAULT_DISKLESS_SYNC_DELAY,
+        diskless_sync_scheduled: false,
+    });
+    // 書き込みコマンドの実行と伝搬をまとめて直列化し、レプリカに届く順序を実行順と一致させる。
+    // 伝搬先がなければ書き込みコマンドは共有で取るので、シャードの異なる書き込みは並列に進む
+    static ref WRITE_LOCK: RwLock<()> = RwLock::new(());
+    // ACK を受け取るたびに通知し、WAIT で待っているクライアントを起こす
+    static ref ACK_RECEIVED: Condvar = Condvar::new();
+    static ref MASTER_LINK: Mutex<MasterLink> = Mutex::new(MasterLink {
+        generation: 0,
+        stream: None,
+        listening_port: String::new(),
+        state: LinkState::Connecting,
+        last_io: None,
+        timeout: DEFAULT_REPL_TIMEOUT,
+    });
+}
+
+struct Replicas {
+    next_id: u64,
+    replicas: Vec<Replica>,
+    // 最後に伝搬した SELECT の DB。None なら次の伝搬で必ず SELECT を送る
+    selected_db: Option<usize>,
+    // 最初のレプリカが接続した時点で作り、以降はレプリカがいなくても伝搬したデータを溜め続ける
+    backlog: Option<Backlog>,
+    backlog_size: usize,
+    // eof に対応したレプリカには、スナップショットを $EOF: で区切ってそのまま流す
+    diskless_sync: bool,
+    // この間に接続してきたレプリカをまとめて、1つのスナップショットで同期する
+    diskless_sync_delay: Duration,
+    diskless_sync_scheduled: bool,
+}
+
+// レプリカ側の、マスターとの接続
+struct MasterLink {
+    // リンクを張り直すたびに増やす。古いリンクのスレッドはこれを見て処理をやめる
+    generation: u64,
+    stream: Option<TcpStream>,
+    // REPLCONF listening-port で伝える自分のポート
+    listening_port: String,
+    state: LinkState,
+    // 最後にマスターからデータを受け取った時刻
+    last_io: Option<Instant>,
+    // これだけの間マスターから何も届かなければ、リンクが死んだとみなして張り直す
+    timeout: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LinkState {
+    // 接続とハンドシェイクの途中、または再接続待ち
+    Connecting,
+    // PSYNC の応答と RDB を受け取っている
+    Sync,
+    // 同期が済み、ストリームを適用している
+    Connected,
+}
+
+// 直近に伝搬したデータを size バイトまで保持するリングバッファ。
+// 切断したレプリカが再接続したとき、ここから差分だけを送る
+struct Backlog {
+    buf: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn new(size: usize) -> Self {
+        Backlog {
+            buf: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+        let overflow = self.buf.len().saturating_sub(self.size);
+        self.buf.drain(..overflow);
+    }
+
+    // バックログの先頭バイトのオフセット。master_repl_offset は最後に伝搬したバイトのオフセット
+    fn first_byte_offset(&self, master_repl_offset: u64) -> u64 {
+        master_repl_offset + 1 - self.buf.len() as u64
+    }
+
+    // psync_offset 以降のデータ。バックログに残っていなければ None
+    fn range_from(&self, master_repl_offset: u64, psync_offset: i64) -> Option<Vec<u8>> {
+        let first = self.first_byte_offset(master_repl_offset) as i64;
+        if psync_offset < first || psync_offset > master_repl_offset as i64 + 1 {
+            return None;
+        }
+        Some(
+            self.buf
+                .iter()
+                .skip((psync_offset - first) as usize)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+// レプリカの書き込みスレッドに渡すもの
+enum Transfer {
+    // 伝搬するコマンドや、+CONTINUE とバックログの差分
+    Data(Vec<u8>),
+    FullResync(FullResync),
+    // ディスクレス同期。RDB を作るスレッドが少しずつ送ってくるものを、閉じられるまでそのまま書く
+    Stream(Receiver<Vec<u8>>),
+}
+
+// +FULLRESYNC の応答と、RDB にして送るスナップショット。RDB を作るのは書き込みスレッドで、
+// その間はロックを取らない
+struct FullResync {
+    header: Vec<u8>,
+    snapshot: store::Snapshot,
+    aux: Vec<(&'static str, String)>,
+}
+
+struct Replica {
+    id: u64,
+    sender: Sender<Transfer>,
+    // 切断するときに使う。書き込みスレッドが止まっても、接続を読んでいるスレッドが持つ限り閉じないため
+    stream: TcpStream,
+    // 接続元の IP と、REPLCONF listening-port で伝えられたポート
+    ip: String,
+    port: String,
+    // REPLCONF capa で伝えられた対応機能 (eof, psync2)
+    capa: Vec<String>,
+    state: ReplicaState,
+    // REPLCONF ACK で報告された処理済みオフセット
+    ack_offset: u64,
+    // 最後に REPLCONF ACK を受け取った時刻
+    last_ack: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReplicaState {
+    // ディスクレス同期のスナップショットが作られるのを待っている
+    WaitBgsave,
+    // FULLRESYNC の RDB や CONTINUE の差分を送っている
+    SendBulk,
+    // 以降は伝搬されたコマンドを受け取っている
+    Online,
+}
+
+impl ReplicaState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReplicaState::WaitBgsave => "wait_bgsave",
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+// INFO replication と ROLE 用
+pub struct ReplicaInfo {
+    pub ip: String,
+    pub port: String,
+    pub capa: Vec<String>,
+    pub state: &'static str,
+    pub offset: u64,
+    // 最後に ACK を受け取ってからの秒数
+    pub lag: u64,
+}
+
+pub fn replicas() -> Vec<ReplicaInfo> {
+    let replicas = REPLICAS.lock().unwrap();
+    replicas
+        .replicas
+        .iter()
+        .map(|replica| ReplicaInfo {
+            ip: replica.ip.clone(),
+            port: replica.port.clone(),
+            capa: replica.capa.clone(),
+            state: replica.state.as_str(),
+            offset: replica.ack_offset,
+            lag: replica.last_ack.elapsed().as_secs(),
+        })
+        .collect()
+}
+
+pub fn write_lock() -> RwLockWriteGuard<'static, ()> {
+    WRITE_LOCK.write().unwrap()
+}
+
+pub enum WriteGuard {
+    Shared(RwLockReadGuard<'static, ()>),
+    Exclusive(RwLockWriteGuard<'static, ()>),
+}
+
+// 書き込みコマンド用。レプリカ、バックログ、AOF のどれかに伝搬するときだけ排他的に取る。
+// 伝搬先は排他的なロックの中でしか増えないので、共有で取っている間に順序が必要になることはない
+pub fn command_write_lock() -> WriteGuard {
+    let guard = WRITE_LOCK.read().unwrap();
+    let propagating = {
+        let replicas = REPLICAS.lock().unwrap();
+        !replicas.replicas.is_empty() || replicas.backlog.is_some()
+    };
+    if !propagating && !aof::enabled() {
+        return WriteGuard::Shared(guard);
+    }
+    drop(guard);
+    WriteGuard::Exclusive(write_lock())
+}
+
+pub fn set_backlog_size(size: usize) {
+    let mut replicas = REPLICAS.lock().unwrap();
+    replicas.backlog_size = size;
+    if let Some(backlog) = replicas.backlog.as_mut() {
+        backlog.size = size;
+        backlog.feed(&[]);
+    }
+}
+
+// INFO replication 用: (repl_backlog_active, repl_backlog_size, repl_backlog_first_byte_offset, repl_backlog_histlen)
+pub fn backlog_info() -> (bool, usize, u64, usize) {
+    let replicas = REPLICAS.lock().unwrap();
+    let master_repl_offset = ServerState::get().master_repl_offset;
+    match replicas.backlog.as_ref() {
+        Some(backlog) => (
+            true,
+            backlog.size,
+            backlog.first_byte_offset(master_repl_offset),
+            backlog.buf.len(),
+        ),
+        None => (false, replicas.backlog_size, 0, 0),
+    }
+}
+
+// PSYNC を受けた接続をレプリカとして登録する。
+// 要求された replid とオフセットの続きがバックログに残っていれば +CONTINUE で差分だけを送り、
+// そうでなければ FULLRESYNC でその時点のスナップショットを RDB にして送る。
+// 書き込みロックを取っている間にスナップショットと登録を済ませ、RDB の作成と転送は書き込みスレッドに任せる。
+// 転送中の書き込みはチャネルに溜まり、RDB の後にそのまま送られる
+pub fn sync_replica(
+    mut stream: TcpStream,
+    client: &Client,
+    replid: &str,
+    psync_offset: i64,
+) -> Option<u64> {
+    let _guard = write_lock();
+    let mut replicas = REPLICAS.lock().unwrap();
+    let state = ServerState::get();
+    // レプリカは自分のマスターと同期できていないと、渡せるデータもオフセットもない
+    if state.role != Role::Master && !master_link_up() {
+        let _ = stream.write_all(
+            &RESP::simple_error("NOMASTERLINK Can't SYNC while not connected with my master")
+                .as_bytes(),
+        );
+        return None;
+    }
+    let backlog_size = replicas.backlog_size;
+    let backlog = replicas
+        .backlog
+        .get_or_insert_with(|| Backlog::new(backlog_size));
+
+    let replid_matches = replid == state.master_replid
+        || (replid == state.master_replid2 && psync_offset <= state.second_repl_offset);
+    let partial = replid_matches
+        .then(|| backlog.range_from(state.master_repl_offset, psync_offset))
+        .flatten();
+
+    match partial {
+        Some(data) => {
+            let mut initial =
+                RESP::SimpleString(format!("CONTINUE {}", state.master_replid)).as_bytes();
+            initial.extend(data);
+            Some(add_replica(
+                &mut replicas,
+                stream,
+                client,
+                Some(Transfer::Data(initial)),
+            ))
+        }
+        None if replicas.diskless_sync && client.capa.iter().any(|capa| capa == "eof") => {
+            let id = add_replica(&mut replicas, stream, client, None);
+            if !replicas.diskless_sync_scheduled {
+                replicas.diskless_sync_scheduled = true;
+                let delay = replicas.diskless_sync_delay;
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    start_diskless_sync();
+                });
+            }
+            Some(id)
+        }
+        None => {
+            let initial = Transfer::FullResync(full_resync(&mut replicas, &state));
+            Some(add_replica(&mut replicas, stream, client, Some(initial)))
+        }
+    }
+}
+
+// 待っているレプリカ全員に、同じスナップショットを $EOF: で区切って送る。
+// ロックの中ではスナップショットを取って各レプリカに受け口を渡すだけで、RDB は別のスレッドで作りながら流す
+fn start_diskless_sync() {
+    let _guard = write_lock();
+    let mut replicas = REPLICAS.lock().unwrap();
+    replicas.diskless_sync_scheduled = false;
+    if !replicas
+        .replicas
+        .iter()
+        .any(|replica| replica.state == ReplicaState::WaitBgsave)
+    {
+        return;
+    }
+    let state = ServerState::get();
+    let sync = full_resync(&mut replicas, &state);
+    let mut senders = vec![];
+    for replica in replicas
+        .replicas
+        .iter_mut()
+        .filter(|replica| replica.state == ReplicaState::WaitBgsave)
+    {
+        let (sender, receiver) = mpsc::sync_channel(DISKLESS_SYNC_MAX_PENDING_CHUNKS);
+        if replica.sender.send(Transfer::Stream(receiver)).is_ok() {
+            senders.push(sender);
+        }
+        replica.state = ReplicaState::SendBulk;
+    }
+    thread::spawn(move || stream_rdb(sync, &random::random_hex(40), senders));
+}
+
+// スナップショットを RDB にしながら、できた分から各レプリカの書き込みスレッドに渡す。
+// 溜まった塊が上限に達すると一番遅いレプリカを待つので、RDB 全体がメモリに載ることはない
+fn stream_rdb(sync: FullResync, eof_mark: &str, mut senders: Vec<SyncSender<Vec<u8>>>) {
+    // 送れなくなったレプリカは外し、誰もいなくなったらやめる
+    let mut send = |chunk: Vec<u8>| {
+        senders.retain(|sender| sender.send(chunk.clone()).is_ok());
+        if senders.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        Ok(())
+    };
+    let mut header = sync.header;
+    header.extend(format!("$EOF:{}\r\n", eof_mark).as_bytes());
+    let _ = send(header)
+        .and_then(|()| {
+            rdb::encode_chunks(
+                &sync.aux,
+                &sync.snapshot.entries(),
+                DISKLESS_SYNC_CHUNK_SIZE,
+                &mut send,
+            )
+        })
+        .and_then(|()| send(eof_mark.as_bytes().to_vec()));
+}
+
+// +FULLRESYNC の応答と、その時点のオフセットに対応するスナップショット。
+// どちらもロックの中で取り、RDB にするのはロックの外で行う
+fn full_resync(replicas: &mut Replicas, state: &ServerState) -> FullResync {
+    let header = RESP::SimpleString(format!(
+        "FULLRESYNC {} {}",
+        state.master_replid, state.master_repl_offset
+    ))
+    .as_bytes();
+    let aux = if state.role == Role::Master {
+        // 新しいレプリカは DB 0 から始まるので、次の伝搬で SELECT を送り直す
+        replicas.selected_db = None;
+        vec![]
+    } else {
+        // 中継するストリームには SELECT を挟めないので、今どの DB を選んでいるかを RDB で伝える
+        let db = replicas.selected_db.unwrap_or(0);
+        vec![("repl-stream-db", db.to_string())]
+    };
+    FullResync {
+        header,
+        snapshot: store::snapshot(),
+        aux,
+    }
+}
+
+fn write_transfer(stream: &mut TcpStream, transfer: Transfer) -> io::Result<()> {
+    match transfer {
+        Transfer::Data(data) => stream.write_all(&data),
+        Transfer::FullResync(FullResync {
+            header,
+            snapshot,
+            aux,
+        }) => {
+            let rdb = rdb::encode(&aux, &snapshot.entries());
+            stream.write_all(&header)?;
+            stream.write_all(&RESP::Rdb(rdb).as_bytes())
+        }
+        Transfer::Stream(receiver) => {
+            for chunk in receiver {
+                stream.write_all(&chunk)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn configure_diskless_sync(enabled: bool, delay: Duration) {
+    let mut replicas = REPLICAS.lock().unwrap();
+    replicas.diskless_sync = enabled;
+    replicas.diskless_sync_delay = delay;
+}
+
+fn add_replica(
+    replicas: &mut Replicas,
+    mut stream: TcpStream,
+    client: &Client,
+    initial: Option<Transfer>,
+) -> u64 {
+    let (sender, receiver) = mpsc::channel::<Transfer>();
+    let id = replicas.next_id;
+    replicas.next_id += 1;
+    replicas.replicas.push(Replica {
+        id,
+        sender,
+        stream: stream.try_clone().unwrap(),
+        ip: stream
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default(),
+        port: client.listening_port.clone().unwrap_or_default(),
+        capa: client.capa.clone(),
+        state: match initial {
+            Some(_) => ReplicaState::SendBulk,
+            None => ReplicaState::WaitBgsave,
+        },
+        ack_offset: 0,
+        last_ack: Instant::now(),
+    });
+
+    // initial が None なら、ディスクレス同期のスナップショットが後から最初のデータとして届く
+    if let Some(initial) = initial {
+        replicas
+            .replicas
+            .last()
+            .unwrap()
+            .sender
+            .send(initial)
+            .unwrap();
+    }
+
+    // 最初のデータを送り終えるまでに伝搬されたコマンドはチャネルに溜まり、その後に送られる
+    thread::spawn(move || {
+        let mut receiver = receiver.into_iter();
+        if let Some(initial) = receiver.next() {
+            if write_transfer(&mut stream, initial).is_ok() {
+                set_replica_online(id);
+                for transfer in receiver {
+                    if write_transfer(&mut stream, transfer).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        remove_replica(id);
+    });
+    id
+}
+
+fn set_replica_online(id: u64) {
+    let mut replicas = REPLICAS.lock().unwrap();
+    if let Some(replica) = replicas
+        .replicas
+        .iter_mut()
+        .find(|replica| replica.id == id)
+    {
+        replica.state = ReplicaState::Online;
+    }
+}
+
+pub fn remove_replica(id: u64) {
+    let mut replicas = REPLICAS.lock().unwrap();
+    replicas.replicas.retain(|replica| replica.id != id);
+}
+
+// 手元のデータや replid が変わったとき、レプリカを切断して同期し直させる
+fn disconnect_replicas(replicas: &mut Replicas) {
+    for replica in replicas.replicas.drain(..) {
+        let _ = replica.stream.shutdown(Shutdown::Both);
+    }
+}
+
+pub fn record_ack(id: u64, offset: u64) {
+    let mut replicas = REPLICAS.lock().unwrap();
+    if let Some(replica) = replicas
+        .replicas
+        .iter_mut()
+        .find(|replica| replica.id == id)
+    {
+        replica.ack_offset = offset;
+        replica.last_ack = Instant::now();
+    }
+    ACK_RECEIVED.notify_all();
+}
+
+// WAIT: offset までを処理済みと報告したレプリカが numreplicas 台以上になるか、
+// タイムアウトするまで待ち、その時点の台数を返す。timeout が None なら無期限に待つ。
+// 待っている間はロックを手放すので他のクライアントは止まらない
+pub fn wait(offset: u64, numreplicas: usize, timeout: Option<Duration>) -> usize {
+    wait_until(numreplicas, timeout, |replica| replica.ack_offset >= offset)
+}
+
+// WAITAOF など、別の条件で ACK を待つコマンドもここを使う
+fn wait_until(
+    numreplicas: usize,
+    timeout: Option<Duration>,
+    acked: impl Fn(&Replica) -> bool,
+) -> usize {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let count = |replicas: &Replicas| replicas.replicas.iter().filter(|r| acked(r)).count();
+
+    let mut replicas = REPLICAS.lock().unwrap();
+    if count(&replicas) >= numreplicas {
+        return count(&replicas);
+    }
+    send_to_replicas(
+        &mut replicas,
+        RedisCommand::Replconf {
+            command: ReplconfCommand::GetAck("*".to_string()),
+        }
+        .to_resp()
+        .as_bytes(),
+    );
+    loop {
+        let acked = count(&replicas);
+        if acked >= numreplicas {
+            return acked;
+        }
+        replicas = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return acked;
+                }
+                ACK_RECEIVED
+                    .wait_timeout(replicas, deadline - now)
+                    .unwrap()
+                    .0
+            }
+            None => ACK_RECEIVED.wait(replicas).unwrap(),
+        };
+    }
+}
+
+// 書き込みコマンドを全レプリカに送り、伝搬後の master_repl_offset を返す。
+// db が前回と違えば先に SELECT を送る
+pub fn propagate(db: usize, command: RedisCommand) -> u64 {
+    let mut replicas = REPLICAS.lock().unwrap();
+    if replicas.replicas.is_empty() && replicas.backlog.is_none() {
+        return ServerState::get().master_repl_offset;
+    }
+    let mut data = vec![];
+    if replicas.selected_db != Some(db) {
+        data.extend(RedisCommand::Select { index: db }.to_resp().as_bytes());
+        replicas.selected_db = Some(db);
+    }
+    data.extend(command.to_resp().as_bytes());
+    send_to_replicas(&mut replicas, data)
+}
+
+// マスター側: period ごとに PING をストリームに流し、レプリカが repl-timeout でリンクの死活を判断できるようにする
+pub fn start_pinger(period: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(period);
+        if ServerState::get().role != Role::Master {
+            continue;
+        }
+        let _guard = write_lock();
+        let mut replicas = REPLICAS.lock().unwrap();
+        if !replicas.replicas.is_empty() {
+            send_to_replicas(&mut replicas, RedisCommand::Ping.to_resp().as_bytes());
+        }
+    });
+}
+
+// 送ったバイト数だけ master_repl_offset を進め、バックログにも積む
+fn send_to_replicas(replicas: &mut Replicas, data: Vec<u8>) -> u64 {
+    let mut offset = 0;
+    ServerState::update(|state| {
+        state.master_repl_offset += data.len() as u64;
+        offset = state.master_repl_offset;
+    });
+    feed_replicas(replicas, data);
+    offset
+}
+
+fn feed_replicas(replicas: &mut Replicas, data: Vec<u8>) {
+    if let Some(backlog) = replicas.backlog.as_mut() {
+        backlog.feed(&data);
+    }
+    // スナップショットを待っているレプリカには送らない (この時点までの内容はスナップショットに含まれる)。
+    // 送信に失敗したレプリカは書き込みスレッドが終了しているので取り除く
+    replicas.replicas.retain(|replica| {
+        replica.state == ReplicaState::WaitBgsave
+            || replica.sender.send(Transfer::Data(data.clone())).is_ok()
+    });
+}
+
+// レプリカ側: マスターから受け取ったバイト列を、作り直さずにそのままサブレプリカとバックログに流す。
+// master_repl_offset は呼び出し側が進める
+fn proxy_to_replicas(db: usize, data: Vec<u8>) {
+    let mut replicas = REPLICAS.lock().unwrap();
+    replicas.selected_db = Some(db);
+    feed_replicas(&mut replicas, data);
+}
+
+pub fn set_listening_port(port: &str) {
+    MASTER_LINK.lock().unwrap().listening_port = port.to_string();
+}
+
+// REPLICAOF host port: 既に同じマスターのレプリカなら false を返す。
+// それまでの replid とオフセットを使って PSYNC するので、同じ履歴を持つマスターなら部分同期で済む
+pub fn become_replica(master_host: String, master_port: String) -> bool {
+    let state = ServerState::get();
+    let role = Role::Slave {
+        master_host: master_host.clone(),
+        master_port: master_port.clone(),
+    };
+    if state.role == role {
+        return false;
+    }
+    let _guard = write_lock();
+    // 自分のレプリカはそのまま残し、新しいマスターからのストリームを中継する。
+    // 全体の同期が必要になったらその時点で切断する
+    ServerState::update(|state| state.role = role);
+    start_replica(
+        master_host,
+        master_port,
+        Some((state.master_replid, state.master_repl_offset)),
+    );
+    true
+}
+
+// REPLICAOF NO ONE: マスターに昇格する。
+// 元のマスターの replid は replid2 に残し、同じ履歴を持つ他のレプリカが部分同期できるようにする
+pub fn become_master() {
+    if ServerState::get().role == Role::Master {
+        return;
+    }
+    stop_master_link();
+    let _guard = write_lock();
+    let mut replicas = REPLICAS.lock().unwrap();
+    replicas.selected_db = None;
+    // replid が変わるので、レプリカには繋ぎ直させる。replid2 とバックログで部分同期できる
+    disconnect_replicas(&mut replicas);
+    ServerState::update(|state| {
+        state.role = Role::Master;
+        state.master_replid2 = std::mem::replace(&mut state.master_replid, random::random_hex(40));
+        state.second_repl_offset = state.master_repl_offset as i64 + 1;
+    });
+}
+
+fn stop_master_link() {
+    let mut link = MASTER_LINK.lock().unwrap();
+    link.generation += 1;
+    link.state = LinkState::Connecting;
+    link.last_io = None;
+    if let Some(stream) = link.stream.take() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+// レプリカ側: マスターとの接続を張り、以降はマスターから届くコマンドを適用し続ける。
+// 既存のリンクがあれば切ってから張り直す
+pub fn start_replica(master_host: String, master_port: String, cached: Option<(String, u64)>) {
+    stop_master_link();
+    let (generation, listening_port) = {
+        let link = MASTER_LINK.lock().unwrap();
+        (link.generation, link.listening_port.clone())
+    };
+    thread::spawn(move || {
+        let address = format!("{}:{}", master_host, master_port);
+        let mut cached = cached;
+        // マスターからの SELECT の状態は再接続をまたいで引き継ぐので、接続の外で持つ
+        let mut client = Client::default();
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        // REPLICAOF で別のリンクに置き換えられるまで、切れるたびに張り直す
+        while is_current_link(generation) {
+            let mut synced = false;
+            let ret = run_master_link(
+                generation,
+                &address,
+                &listening_port,
+                cached.clone(),
+                &mut client,
+                &mut synced,
+            );
+            set_link_state(generation, LinkState::Connecting);
+            if !is_current_link(generation) {
+                break;
+            }
+            if let Err(e) = ret {
+                println!("master link error: {}", e);
+            }
+            // 一度同期できていれば、次は手元の replid とオフセットで部分同期を試みる
+            if synced {
+                let state = ServerState::get();
+                cached = Some((state.master_replid, state.master_repl_offset));
+                backoff = MIN_RECONNECT_BACKOFF;
+            }
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    });
+}
+
+fn is_current_link(generation: u64) -> bool {
+    MASTER_LINK.lock().unwrap().generation == generation
+}
+
+fn run_master_link(
+    generation: u64,
+    address: &str,
+    listening_port: &str,
+    cached: Option<(String, u64)>,
+    client: &mut Client,
+    synced: &mut bool,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    {
+        let mut link = MASTER_LINK.lock().unwrap();
+        if link.generation != generation {
+            return Ok(());
+        }
+        // マスターは定期的に PING を送ってくるので、timeout の間何も届かなければ死んでいる
+        stream.set_read_timeout(Some(link.timeout))?;
+        link.stream = Some(stream.try_clone()?);
+    }
+    let mut node = Node::new(stream);
+    handshake(&mut node, listening_port, cached)?;
+    set_link_state(generation, LinkState::Sync);
+    sync_with_master(&mut node, client)?;
+    *synced = true;
+    set_link_state(generation, LinkState::Connected);
+    apply_master_stream(&mut node, client)
+}
+
+fn set_link_state(generation: u64, state: LinkState) {
+    let mut link = MASTER_LINK.lock().unwrap();
+    if link.generation == generation {
+        link.state = state;
+    }
+}
+
+fn touch_link() {
+    MASTER_LINK.lock().unwrap().last_io = Some(Instant::now());
+}
+
+pub fn set_repl_timeout(timeout: Duration) {
+    MASTER_LINK.lock().unwrap().timeout = timeout;
+}
+
+// マスターとの同期が済んでいて、レプリケーションストリームを受け取れる状態か
+pub fn master_link_up() -> bool {
+    MASTER_LINK.lock().unwrap().state == LinkState::Connected
+}
+
+// ROLE 用のリンクの状態
+pub fn master_link_state() -> &'static str {
+    match MASTER_LINK.lock().unwrap().state {
+        LinkState::Connecting => "connecting",
+        LinkState::Sync => "sync",
+        LinkState::Connected => "connected",
+    }
+}
+
+// INFO replication 用: (master_link_status が up か, master_last_io_seconds_ago, master_sync_in_progress)
+pub fn master_link_info() -> (bool, Option<u64>, bool) {
+    let link = MASTER_LINK.lock().unwrap();
+    (
+        link.state == LinkState::Connected,
+        link.last_io.map(|last_io| last_io.elapsed().as_secs()),
+        link.state == LinkState::Sync,
+    )
+}
+
+// cached は以前の同期で得た (replid, 処理済みオフセット)。あれば PSYNC で続きから要求する
+fn handshake(
+    node: &mut Node,
+    listening_port: &str,
+    cached: Option<(String, u64)>,
+) -> io::Result<()> {
+    node.write(RedisCommand::Ping.to_resp())?;
+    node.read()?;
+
+    node.write(
+        RedisCommand::Replconf {
+            command: ReplconfCommand::ListeningPort(listening_port.to_string()),
+        }
+        .to_resp(),
+    )?;
+    node.read()?;
+
+    node.write(
+        RedisCommand::Replconf {
+            command: ReplconfCommand::Capa(vec!["eof".to_string(), "psync2".to_string()]),
+        }
+        .to_resp(),
+    )?;
+    node.read()?;
+
+    let (master_replid, master_repl_offset) = match cached {
+        Some((replid, offset)) => (replid, offset as i64 + 1),
+        None => ("?".to_string(), -1),
+    };
+    node.write(
+        RedisCommand::Psync {
+            master_replid,
+            master_repl_offset,
+        }
+        .to_resp(),
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum PsyncReply {
+    // +FULLRESYNC <replid> <offset>
+    FullResync { replid: String, offset: u64 },
+    // +CONTINUE [<replid>]
+    Continue { replid: Option<String> },
+}
+
+fn sync_with_master(node: &mut Node, client: &mut Client) -> io::Result<()> {
+    let reply = match node.read()? {
+        RESP::SimpleString(s) => parse_psync_reply(&s),
+        // マスター自身が同期中などで PSYNC を受け付けられないときはエラーが返るので、後で再試行する
+        resp => {
+            return Err(io::Error::other(format!(
+                "unexpected response to PSYNC: {:?}",
+                resp
+            )))
+        }
+    };
+    touch_link();
+    match reply {
+        PsyncReply::FullResync { replid, offset } => {
+            let rdb = node.read_rdb()?;
+            touch_link();
+            // 壊れた RDB を受け取っても今のデータを残せるよう、すべて読めてから差し替える
+            let rdb::Rdb { aux, entries, .. } =
+                rdb::parse(&rdb).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            {
+                let _guard = write_lock();
+                store::replace_all(
+                    entries
+                        .into_iter()
+                        .map(|entry| (entry.db, entry.key, entry.value, entry.expires_at)),
+                );
+                *client = Client::default();
+                // マスター自身もレプリカなら、中継されてくるストリームが選んでいる DB が書かれている
+                if let Some((_, db)) = aux.iter().find(|(key, _)| key == "repl-stream-db") {
+                    client.db = db.parse().unwrap_or(0);
+                }
+                ServerState::update(|state| {
+                    state.master_replid = replid;
+                    state.master_repl_offset = offset;
+                });
+                // 履歴が変わったので、サブレプリカは同期し直し、バックログも新しいオフセットから作り直す
+                let mut replicas = REPLICAS.lock().unwrap();
+                disconnect_replicas(&mut replicas);
+                replicas.backlog = Some(Backlog::new(replicas.backlog_size));
+            }
+            // それまでの AOF は受け取ったデータとつながらないので作り直す
+            if let Err(e) = aof::restart_after_sync() {
+                println!("failed to restart the AOF after a full sync: {}", e);
+            }
+        }
+        // マスターがフェイルオーバーで replid を変えていれば、古い replid を replid2 に残す
+        PsyncReply::Continue {
+            replid: Some(replid),
+        } => {
+            let _guard = write_lock();
+            let mut replicas = REPLICAS.lock().unwrap();
+            ServerState::update(|state| {
+                if state.master_replid != replid {
+                    state.master_replid2 = std::mem::replace(&mut state.master_replid, replid);
+                    state.second_repl_offset = state.master_repl_offset as i64 + 1;
+                    // サブレプリカにも新しい replid を伝えるため、繋ぎ直させる
+                    disconnect_replicas(&mut replicas);
+                }
+            });
+        }
+        PsyncReply::Continue { replid: None } => {}
+    }
+    Ok(())
+}
+
+// マスターからのコマンドには応答を返さない。
+// ただし GETACK には、その GETACK を受け取る直前までに処理したバイト数を返す
+fn apply_master_stream(node: &mut Node, client: &mut Client) -> io::Result<()> {
+    // 何も届かなくても REPLCONF_ACK_PERIOD ごとに ACK を送り、マスターに生存とオフセットを伝える
+    node.set_read_timeout(Some(REPLCONF_ACK_PERIOD))?;
+    loop {
+        let (resp, raw) = match node.read_raw() {
+            Ok(ret) => ret,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                let link = MASTER_LINK.lock().unwrap();
+                if link
+                    .last_io
+                    .is_some_and(|last_io| last_io.elapsed() >= link.timeout)
+                {
+                    return Err(e);
+                }
+                drop(link);
+                send_ack(node)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        touch_link();
+        let command = RedisCommand::new(resp);
+        let getack = matches!(
+            command,
+            RedisCommand::Replconf {
+                command: ReplconfCommand::GetAck(_)
+            }
+        );
+        if getack {
+            send_ack(node)?;
+        }
+        // 適用・オフセット・中継をまとめて行い、サブレプリカ向けのスナップショットと食い違わないようにする
+        let _guard = write_lock();
+        if !getack {
+            handler::handle_redis_command(command, client);
+        }
+        ServerState::update(|state| state.master_repl_offset += raw.len() as u64);
+        proxy_to_replicas(client.db, raw);
+    }
+}
+
+fn send_ack(node: &mut Node) -> io::Result<()> {
+    let offset = ServerState::get().master_repl_offset;
+    node.write(
+        RedisCommand::Replconf {
+            command: ReplconfCommand::Ack(offset),
+        }
+        .to_resp(),
+    )
+}
+
+fn parse_psync_reply(s: &str) -> PsyncReply {
+    let mut iter = s.split(' ');
+    match (iter.next(), iter.next(), iter.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => PsyncReply::FullResync {
+            replid: replid.to_string(),
+            offset: offset.parse().unwrap(),
+        },
+        (Some("CONTINUE"), replid, None) => PsyncReply::Continue {
+            replid: replid.map(|replid| replid.to_string()),
+        },
+        _ => panic!("invalid PSYNC reply: {}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Data;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    // ServerState と REPLICAS はグローバルなので、書き換えるテストは同時に走らせない
+    static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_propagate() {
+        let _guard = STATE_LOCK.lock().unwrap();
+        ServerState::init(&crate::server_state::Role::Master);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut replica = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        add_replica(
+            &mut REPLICAS.lock().unwrap(),
+            stream,
+            &Client::default(),
+            Some(Transfer::Data(vec![])),
+        );
+
+        propagate(
+            1,
+            RedisCommand::Del {
+                keys: vec!["key".to_string()],
+            },
+        );
+        propagate(
+            1,
+            RedisCommand::Del {
+                keys: vec!["key2".to_string()],
+            },
+        );
+
+        let expected = [
+            RESP::Array(vec![RESP::bulk_strings("SELECT"), RESP::bulk_strings("1")]),
+            RESP::Array(vec![RESP::bulk_strings("DEL"), RESP::bulk_strings("key")]),
+            RESP::Array(vec![RESP::bulk_strings("DEL"), RESP::bulk_strings("key2")]),
+        ]
+        .into_iter()
+        .flat_map(RESP::as_bytes)
+        .collect::<Vec<_>>();
+        let mut buf = vec![0; expected.len()];
+        replica.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(ServerState::get().master_repl_offset, expected.len() as u64);
+    }
+
+    #[test]
+    fn test_full_resync() {
+        let _guard = STATE_LOCK.lock().unwrap();
+        ServerState::init(&crate::server_state::Role::Master);
+        store::set_data(
+            0,
+            "replication:full_resync",
+            Data::String("before".to_string()),
+            None,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let replica = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let id = {
+            let _guard = write_lock();
+            let mut replicas = REPLICAS.lock().unwrap();
+            let state = ServerState::get();
+            let initial = Transfer::FullResync(full_resync(&mut replicas, &state));
+            add_replica(&mut replicas, stream, &Client::default(), Some(initial))
+        };
+        // スナップショットを取った後の書き込みは RDB に入らない
+        store::set_data(
+            0,
+            "replication:full_resync",
+            Data::String("after".to_string()),
+            None,
+        );
+
+        let mut replica = Node::new(replica);
+        let state = ServerState::get();
+        assert_eq!(
+            replica.read().unwrap(),
+            RESP::SimpleString(format!(
+                "FULLRESYNC {} {}",
+                state.master_replid, state.master_repl_offset
+            ))
+        );
+        let rdb = rdb::parse(&replica.read_rdb().unwrap()).unwrap();
+        let entry = rdb
+            .entries
+            .iter()
+            .find(|entry| entry.key == "replication:full_resync")
+            .unwrap();
+        assert_eq!(entry.value, Data::String("before".to_string()));
+        remove_replica(id);
+        store::del(0, &["replication:full_resync".to_string()]);
+    }
+
+    #[test]
+    fn test_diskless_sync() {
+        let _guard = STATE_LOCK.lock().unwrap();
+        ServerState::init(&crate::server_state::Role::Master);
+        // 塊の大きさを超える値で、RDB が何回かに分けて送られるようにする
+        let value = Data::String("v".repeat(DISKLESS_SYNC_CHUNK_SIZE * 2));
+        store::set_data(0, "replication:diskless_sync", value.clone(), None);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let replica = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let id = add_replica(
+            &mut REPLICAS.lock().unwrap(),
+            stream,
+            &Client::default(),
+            None,
+        );
+        start_diskless_sync();
+        store::del(0, &["replication:diskless_sync".to_string()]);
+
+        let mut replica = Node::new(replica);
+        let state = ServerState::get();
+        assert_eq!(
+            replica.read().unwrap(),
+            RESP::SimpleString(format!(
+                "FULLRESYNC {} {}",
+                state.master_replid, state.master_repl_offset
+            ))
+        );
+        let rdb = rdb::parse(&replica.read_rdb().unwrap()).unwrap();
+        let entry = rdb
+            .entries
+            .iter()
+            .find(|entry| entry.key == "replication:diskless_sync")
+            .unwrap();
+        assert_eq!(entry.value, value);
+        remove_replica(id);
+    }
+
+    #[test]
+    fn test_replica_local_write() {
+        let _guard = STATE_LOCK.lock().unwrap();
+        ServerState::init(&Role::Slave {
+            master_host: "127.0.0.1".to_string(),
+            master_port: "6379".to_string(),
+        });
+        ServerState::update(|state| {
+            state.replica_read_only = false;
+            state.master_repl_offset = 100;
+        });
+        // FULLRESYNC 後のレプリカはバックログを持っている
+        REPLICAS.lock().unwrap().backlog = Some(Backlog::new(DEFAULT_BACKLOG_SIZE));
+
+        let mut client = Client::default();
+        let ret = handler::handle_write_command(
+            RedisCommand::Set {
+                key: "replica-local".to_string(),
+                value: "v".to_string(),
+                options: vec![],
+            },
+            &mut client,
+        );
+        assert_eq!(ret, vec![RESP::simple_string("OK")]);
+        assert_eq!(store::get(0, "replica-local"), Ok(Some("v".to_string())));
+        // 手元に適用するだけで、オフセットもバックログも進まない
+        assert_eq!(ServerState::get().master_repl_offset, 100);
+        assert_eq!(client.last_write_offset, 0);
+        let backlog = REPLICAS.lock().unwrap().backlog.take().unwrap();
+        assert!(backlog.buf.is_empty());
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8);
+        assert_eq!(backlog.first_byte_offset(0), 1);
+        assert_eq!(backlog.range_from(0, 1), Some(vec![]));
+
+        backlog.feed(b"abcde");
+        assert_eq!(backlog.first_byte_offset(5), 1);
+        assert_eq!(backlog.range_from(5, 3), Some(b"cde".to_vec()));
+
+        // 溢れた分は先頭から捨てられる
+        backlog.feed(b"fghij");
+        assert_eq!(backlog.first_byte_offset(10), 3);
+        assert_eq!(backlog.range_from(10, 3), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.range_from(10, 11), Some(vec![]));
+        assert_eq!(backlog.range_from(10, 2), None);
+        assert_eq!(backlog.range_from(10, 12), None);
+    }
+
+    #[test]
+    fn test_parse_psync_reply() {
+        assert_eq!(
+            parse_psync_reply("FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 42"),
+            PsyncReply::FullResync {
+                replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
+                offset: 42
+            }
+        );
+        assert_eq!(
+            parse_psync_reply("CONTINUE 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"),
+            PsyncReply::Continue {
+                replid: Some("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string())
+            }
+        );
+        assert_eq!(
+            parse_psync_reply("CONTINUE"),
+            PsyncReply::Continue { replid: None }
+        );
+    }
+}
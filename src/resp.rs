@@ -1,6 +1,18 @@
+// 値は String で持つが、中身はバイナリセーフに扱う。
+// 受信したバイトはそれぞれ U+0000..=U+00FF の1文字に対応させ、送信時は逆変換する
+pub fn bytes_to_string(data: &[u8]) -> String {
+    data.iter().map(|&x| x as char).collect()
+}
+
+pub fn string_to_bytes(s: &str) -> Vec<u8> {
+    s.chars().map(|c| c as u32 as u8).collect()
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum RESP {
     SimpleString(String),
+    SimpleError(String),
+    Integer(i64),
     BulkStrings(String),
     NullBulkStrings,
     Array(Vec<RESP>),
@@ -8,89 +20,121 @@ pub enum RESP {
 }
 
 impl RESP {
-    pub fn to_string(self) -> String {
+    pub fn as_bytes(self) -> Vec<u8> {
+        let mut ret = vec![];
+        self.write_bytes(&mut ret);
+        ret
+    }
+
+    fn write_bytes(self, ret: &mut Vec<u8>) {
         match self {
-            Self::SimpleString(s) => format!("+{}\r\n", s),
-            Self::BulkStrings(s) => format!("${}\r\n{}\r\n", s.len(), s),
-            Self::NullBulkStrings => "$-1\r\n".to_string(),
+            Self::SimpleString(s) => {
+                ret.push(b'+');
+                ret.extend(string_to_bytes(&s));
+                ret.extend(b"\r\n");
+            }
+            Self::SimpleError(s) => {
+                ret.push(b'-');
+                ret.extend(string_to_bytes(&s));
+                ret.extend(b"\r\n");
+            }
+            Self::Integer(n) => ret.extend(format!(":{}\r\n", n).as_bytes()),
+            Self::BulkStrings(s) => {
+                let data = string_to_bytes(&s);
+                ret.extend(format!("${}\r\n", data.len()).as_bytes());
+                ret.extend(data);
+                ret.extend(b"\r\n");
+            }
+            Self::NullBulkStrings => ret.extend(b"$-1\r\n"),
             Self::Array(array) => {
-                let mut ret = format!("*{}\r\n", array.len());
+                ret.extend(format!("*{}\r\n", array.len()).as_bytes());
                 for resp in array {
-                    ret.push_str(&resp.to_string());
+                    resp.write_bytes(ret);
                 }
-                ret
             }
-            Self::Rdb(_) => unimplemented!("to_string for Rdb is not implemented yet"),
-        }
-    }
-
-    pub fn as_bytes(self) -> Vec<u8> {
-        match self {
+            // RDB の転送は末尾に CRLF を付けない
             Self::Rdb(data) => {
-                let length_section = format!("${}\r\n", data.len());
-                let data_section = data.as_slice();
-                let mut ret = Vec::with_capacity(length_section.len() + data_section.len());
-                ret.extend_from_slice(length_section.as_bytes());
-                ret.extend_from_slice(data_section);
-                ret
+                ret.extend(format!("${}\r\n", data.len()).as_bytes());
+                ret.extend(data);
             }
-            _ => self.to_string().as_bytes().to_vec(),
         }
     }
 
     pub fn from_bytes(data: &[u8]) -> Self {
-        let mut iter = data.iter().map(|&x| x as char);
-        let ret = Self::parse(&mut iter);
-        ret
+        match Self::parse(data) {
+            Some((resp, _)) => resp,
+            None => panic!("incomplete data"),
+        }
     }
 
-    fn parse(iter: &mut impl Iterator<Item = char>) -> Self {
-        let ret = match iter.next() {
-            Some('+') => Self::parse_simple_string(iter),
-            Some('$') => Self::parse_bulk_strings(iter),
-            Some('*') => Self::parse_array(iter),
+    // data の先頭から1つ分をパースし、値と消費したバイト数を返す。
+    // データが足りなければ None を返すので、続きを受信してから再度呼ぶ
+    pub fn parse(data: &[u8]) -> Option<(Self, usize)> {
+        let (line, mut pos) = read_line(data, 1)?;
+        let ret = match data[0] {
+            b'+' => Self::SimpleString(bytes_to_string(line)),
+            b'-' => Self::SimpleError(bytes_to_string(line)),
+            b':' => Self::Integer(parse_number(line)),
+            b'$' => {
+                let n = parse_number(line);
+                if n == -1 {
+                    return Some((Self::NullBulkStrings, pos));
+                }
+                let n = n as usize;
+                if data.len() < pos + n + 2 {
+                    return None;
+                }
+                assert_eq!(&data[pos + n..pos + n + 2], b"\r\n");
+                let s = bytes_to_string(&data[pos..pos + n]);
+                pos += n + 2;
+                Self::BulkStrings(s)
+            }
+            b'*' => {
+                let n = parse_number(line);
+                let mut array = vec![];
+                for _ in 0..n {
+                    let (resp, size) = Self::parse(&data[pos..])?;
+                    array.push(resp);
+                    pos += size;
+                }
+                Self::Array(array)
+            }
             _ => panic!("unknown type"),
         };
-        ret
+        Some((ret, pos))
     }
 
-    fn parse_simple_string(iter: &mut impl Iterator<Item = char>) -> Self {
-        // '+' is already consumed
-        let s = iter.take_while(|&x| x != '\r').collect::<String>();
-        assert_eq!(iter.next(), Some('\n'));
-        Self::SimpleString(s)
-    }
-
-    fn parse_bulk_strings(iter: &mut impl Iterator<Item = char>) -> Self {
-        // '$' is already consumed
-        let n = iter.take_while(|&x| x != '\r').collect::<String>();
-        assert_eq!(iter.next(), Some('\n'));
-
-        if n == "-1" {
-            return Self::NullBulkStrings;
+    // FULLRESYNC に続く RDB を読む。末尾に CRLF がない点が通常の bulk string と異なる。
+    // searched は目印を探し終えた位置で、続きを受信して再度呼ぶときは前回の続きから探す
+    pub fn parse_rdb(data: &[u8], searched: &mut usize) -> Option<(Self, usize)> {
+        match data.first() {
+            Some(b'$') => {}
+            Some(_) => panic!("invalid rdb"),
+            None => return None,
         }
-
-        let n = n.parse::<usize>().unwrap();
-        let s = iter.take_while(|&x| x != '\r').collect::<String>();
-        assert_eq!(iter.next(), Some('\n'));
-        assert_eq!(s.len(), n);
-        Self::BulkStrings(s)
-    }
-
-    fn parse_array(iter: &mut impl Iterator<Item = char>) -> Self {
-        // '*' is already consumed
-        let n = iter
-            .take_while(|&x| x != '\r')
-            .collect::<String>()
-            .parse::<usize>()
-            .unwrap();
-        assert_eq!(iter.next(), Some('\n'));
-
-        let mut array = vec![];
-        for _ in 0..n {
-            array.push(Self::parse(iter));
+        let (line, pos) = read_line(data, 1)?;
+        // ディスクレス同期では長さの代わりに $EOF:<40 バイトの目印> が来て、RDB の後に同じ目印が続く
+        if let Some(mark) = line.strip_prefix(b"EOF:") {
+            let start = (*searched).max(pos);
+            let Some(len) = data[start..]
+                .windows(mark.len())
+                .position(|window| window == mark)
+            else {
+                // 目印の途中までを受信しているかもしれないので、末尾の mark.len() - 1 バイトは次回も見る
+                *searched = data.len().saturating_sub(mark.len() - 1).max(pos);
+                return None;
+            };
+            let len = start - pos + len;
+            return Some((
+                Self::Rdb(data[pos..pos + len].to_vec()),
+                pos + len + mark.len(),
+            ));
         }
-        Self::Array(array)
+        let n = parse_number(line) as usize;
+        if data.len() < pos + n {
+            return None;
+        }
+        Some((Self::Rdb(data[pos..pos + n].to_vec()), pos + n))
     }
 
     pub fn simple_string(s: &str) -> Self {
@@ -100,6 +144,20 @@ impl RESP {
     pub fn bulk_strings(s: &str) -> Self {
         Self::BulkStrings(s.to_string())
     }
+
+    pub fn simple_error(s: &str) -> Self {
+        Self::SimpleError(s.to_string())
+    }
+}
+
+// start 以降の CRLF までを返す。2つ目の値は CRLF の次の位置
+fn read_line(data: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let len = data.get(start..)?.windows(2).position(|w| w == b"\r\n")?;
+    Some((&data[start..start + len], start + len + 2))
+}
+
+fn parse_number(line: &[u8]) -> i64 {
+    bytes_to_string(line).parse().unwrap()
 }
 
 #[cfg(test)]
@@ -114,6 +172,11 @@ mod tests {
             b"$5\r\nvalue\r\n"
         );
         assert_eq!(RESP::NullBulkStrings.as_bytes(), b"$-1\r\n");
+        assert_eq!(RESP::Integer(42).as_bytes(), b":42\r\n");
+        assert_eq!(
+            RESP::SimpleError("ERR no such key".to_string()).as_bytes(),
+            b"-ERR no such key\r\n"
+        );
         assert_eq!(
             RESP::Array(vec![
                 RESP::SimpleString("OK".to_string()),
@@ -139,6 +202,11 @@ mod tests {
             RESP::BulkStrings("value".to_string())
         );
         assert_eq!(RESP::from_bytes(b"$-1\r\n"), RESP::NullBulkStrings);
+        assert_eq!(RESP::from_bytes(b":-3\r\n"), RESP::Integer(-3));
+        assert_eq!(
+            RESP::from_bytes(b"-ERR no such key\r\n"),
+            RESP::SimpleError("ERR no such key".to_string())
+        );
         assert_eq!(
             RESP::from_bytes(b"*2\r\n+OK\r\n$5\r\nvalue\r\n"),
             RESP::Array(vec![
@@ -147,4 +215,62 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_parse() {
+        let data = b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$3\r\na\r\n\r\n";
+        let (resp, size) = RESP::parse(data).unwrap();
+        assert_eq!(resp, RESP::Array(vec![RESP::bulk_strings("PING")]));
+        assert_eq!(size, 14);
+        // bulk string は長さで読むので CRLF を含んでもよい
+        let (resp, size) = RESP::parse(&data[size..]).unwrap();
+        assert_eq!(
+            resp,
+            RESP::Array(vec![
+                RESP::bulk_strings("ECHO"),
+                RESP::bulk_strings("a\r\n")
+            ])
+        );
+        assert_eq!(size, data.len() - 14);
+
+        assert_eq!(RESP::parse(b"*2\r\n$4\r\nECHO\r\n$3\r\na"), None);
+        assert_eq!(RESP::parse(b"$5\r\nva"), None);
+        assert_eq!(RESP::parse(b"+O"), None);
+    }
+
+    #[test]
+    fn test_binary_safe() {
+        let data = [0x00, 0xff, 0x80, b'\r'];
+        let resp = RESP::BulkStrings(bytes_to_string(&data));
+        let bytes = resp.clone().as_bytes();
+        assert_eq!(bytes, b"$4\r\n\x00\xff\x80\r\r\n");
+        assert_eq!(RESP::from_bytes(&bytes), resp);
+    }
+
+    #[test]
+    fn test_parse_rdb() {
+        let data = b"$3\r\n\x01\x02\x03*1\r\n";
+        assert_eq!(
+            RESP::parse_rdb(data, &mut 0),
+            Some((RESP::Rdb(vec![0x01, 0x02, 0x03]), 7))
+        );
+        assert_eq!(RESP::parse_rdb(b"$3\r\n\x01", &mut 0), None);
+
+        let mark = "0123456789abcdef0123456789abcdef01234567";
+        let data = format!("$EOF:{}\r\nREDIS{}*1\r\n", mark, mark);
+        assert_eq!(
+            RESP::parse_rdb(data.as_bytes(), &mut 0),
+            Some((RESP::Rdb(b"REDIS".to_vec()), 92))
+        );
+
+        // 目印が分かれて届いても、前回探し終えた位置から続けて見つけられる
+        let mut searched = 0;
+        let partial = format!("$EOF:{}\r\nREDIS{}", mark, &mark[..39]);
+        assert_eq!(RESP::parse_rdb(partial.as_bytes(), &mut searched), None);
+        assert_eq!(searched, 52);
+        assert_eq!(
+            RESP::parse_rdb(data.as_bytes(), &mut searched),
+            Some((RESP::Rdb(b"REDIS".to_vec()), 92))
+        );
+    }
 }
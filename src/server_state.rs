@@ -1,10 +1,11 @@
-use core::str;
+use crate::random;
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use crate::store;
-
-const ROLE_KEY: &str = "__role__";
-const MASTER_REPLID_KEY: &str = "__master_replid__";
-const MASTER_REPL_OFFSET_KEY: &str = "__master_repl_offset__";
+lazy_static! {
+    static ref STATE: Mutex<Option<ServerState>> = Mutex::new(None);
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Role {
@@ -15,79 +16,67 @@ pub enum Role {
     },
 }
 
-// masterなら "master"
-// slaveなら "slave,host,port"
-impl Role {
-    fn from_string(role: &str) -> Self {
-        if role == "master" {
-            Role::Master
-        } else if role.starts_with("slave") {
-            let mut iter = role.split(',');
-            iter.next(); // consume "slave"
-            let master_host = iter.next().unwrap().to_string();
-            let master_port = iter.next().unwrap().to_string();
-            Role::Slave {
-                master_host,
-                master_port,
-            }
-        } else {
-            panic!("unknown role");
-        }
-    }
-
-    fn to_string(&self) -> String {
-        match self {
-            Role::Master => "master".to_string(),
-            Role::Slave {
-                master_host,
-                master_port,
-            } => format!("slave,{},{}", master_host, master_port),
-        }
-    }
-}
-
+#[derive(Clone)]
 pub struct ServerState {
     pub role: Role,
     pub master_replid: String,
     pub master_repl_offset: u64,
+    // フェイルオーバー前のマスターの replid。second_repl_offset までのオフセットなら
+    // こちらの replid での PSYNC も部分同期できる (PSYNC2)
+    pub master_replid2: String,
+    pub second_repl_offset: i64,
+    // レプリカのとき、マスター以外からの書き込みを拒否する
+    pub replica_read_only: bool,
+    // レプリカのとき、マスターとのリンクが切れていても古いデータで応答する
+    pub replica_serve_stale_data: bool,
+    // RDB ファイルの置き場所
+    pub dir: String,
+    pub dbfilename: String,
+    // AOF も dir に置く
+    pub appendfilename: String,
+    pub appenddirname: String,
 }
 
 impl ServerState {
     pub fn init(role: &Role) {
         Self::set(ServerState {
             role: role.clone(),
-            master_replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(),
+            master_replid: random::random_hex(40),
             master_repl_offset: 0,
+            master_replid2: "0".repeat(40),
+            second_repl_offset: -1,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
         });
     }
 
+    pub fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    pub fn aof_dir(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.appenddirname)
+    }
+
     pub fn set(s: Self) {
-        store::set(ROLE_KEY, &s.role.to_string(), None);
-        store::set(MASTER_REPLID_KEY, &s.master_replid, None);
-        store::set(
-            MASTER_REPL_OFFSET_KEY,
-            &s.master_repl_offset.to_string(),
-            None,
-        );
+        *STATE.lock().unwrap() = Some(s);
+    }
+
+    pub fn update(f: impl FnOnce(&mut Self)) {
+        match STATE.lock().unwrap().as_mut() {
+            Some(state) => f(state),
+            None => panic!("server state not initialized"),
+        }
     }
 
     pub fn get() -> Self {
-        let role = match store::get(ROLE_KEY) {
-            Some(role) => Role::from_string(&role),
-            None => panic!("role not found"),
-        };
-        let master_replid = match store::get(MASTER_REPLID_KEY) {
-            Some(master_replid) => master_replid,
-            None => panic!("master_replid not found"),
-        };
-        let master_repl_offset = match store::get(MASTER_REPL_OFFSET_KEY) {
-            Some(master_repl_offset) => master_repl_offset.parse().unwrap(),
-            None => panic!("master_repl_offset not found"),
-        };
-        ServerState {
-            role,
-            master_replid,
-            master_repl_offset,
+        match STATE.lock().unwrap().as_ref() {
+            Some(state) => state.clone(),
+            None => panic!("server state not initialized"),
         }
     }
 }
@@ -1,46 +1,939 @@
+use crate::data::Data;
+use crate::glob::glob_match;
+use crate::random;
 use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
 use std::time;
+use thiserror::Error;
 
+pub const DEFAULT_DATABASES: usize = 16;
+pub const SHARDS: usize = 16;
+
+// キー1つあたりの HashMap やメタデータのおおよそのオーバーヘッド
+const ENTRY_OVERHEAD: usize = 64;
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MINUTES: u128 = 1;
+
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static EVICTED_KEYS: AtomicU64 = AtomicU64::new(0);
+
+// ロックの順序は必ず STORE (RwLock) -> シャード (db 番号, シャード番号の昇順) とする。
+// 通常のコマンドは STORE の読み取りロックとキーのシャードのロックだけを取るので、
+// 異なるシャードへのアクセスは並列に進む。DB 全体を入れ替える操作だけが書き込みロックを取る。
 lazy_static! {
-    static ref STORE: Mutex<HashMap<String, Value>> = Mutex::new(HashMap::new());
+    static ref STORE: RwLock<Vec<Database>> = RwLock::new(new_databases(DEFAULT_DATABASES));
+    static ref EVICTION: RwLock<EvictionConfig> = RwLock::new(EvictionConfig::default());
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MaxmemoryPolicy {
+    NoEviction,
+    AllkeysLru,
+    VolatileLru,
+    AllkeysLfu,
+    VolatileLfu,
+    AllkeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn new(policy: &str) -> Self {
+        match policy {
+            "noeviction" => MaxmemoryPolicy::NoEviction,
+            "allkeys-lru" => MaxmemoryPolicy::AllkeysLru,
+            "volatile-lru" => MaxmemoryPolicy::VolatileLru,
+            "allkeys-lfu" => MaxmemoryPolicy::AllkeysLfu,
+            "volatile-lfu" => MaxmemoryPolicy::VolatileLfu,
+            "allkeys-random" => MaxmemoryPolicy::AllkeysRandom,
+            "volatile-random" => MaxmemoryPolicy::VolatileRandom,
+            "volatile-ttl" => MaxmemoryPolicy::VolatileTtl,
+            _ => panic!("unknown maxmemory policy"),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllkeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllkeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::AllkeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    fn volatile_only(&self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EvictionConfig {
+    // 0 なら無制限
+    pub maxmemory: usize,
+    pub policy: MaxmemoryPolicy,
+    pub samples: usize,
+}
+
+impl Default for EvictionConfig {
+    fn default() -> Self {
+        EvictionConfig {
+            maxmemory: 0,
+            policy: MaxmemoryPolicy::NoEviction,
+            samples: 5,
+        }
+    }
+}
+
+// シャード1つ分のキースペース。挿入・削除のたびに USED_MEMORY を更新する。
+// キーを無作為に選べるよう、キーの並びも持つ。各 Value の slot がその中の位置を指す。
+// map はスナップショットと共有し、スナップショットが残っている間に書き換えるときだけ複製する (コピーオンライト)。
+// 値は Arc で持つので、複製されるのはキーとメタデータだけで、リストなどの中身は共有したままになる
+#[derive(Default)]
+struct Db {
+    map: Arc<HashMap<String, Value>>,
+    slots: Vec<String>,
+    // 有効期限付きのキーだけの並び (volatile-* ポリシー用)。Value の volatile_slot が位置を指す
+    volatile: Vec<String>,
+    used_memory: usize,
+}
+
+impl Db {
+    // 既存のキーを上書きするときは、並びの中の位置を変えない
+    fn insert(&mut self, key: String, mut value: Value) -> Option<Value> {
+        let size = entry_size(&key, &value);
+        self.used_memory += size;
+        USED_MEMORY.fetch_add(size, Ordering::Relaxed);
+        let (slot, volatile_slot) = match self.map.get(&key) {
+            Some(old) => (old.slot, old.volatile_slot),
+            None => {
+                self.slots.push(key.clone());
+                (self.slots.len() - 1, None)
+            }
+        };
+        value.slot = slot;
+        value.volatile_slot = match (volatile_slot, value.expires_at) {
+            (Some(index), Some(_)) => Some(index),
+            (Some(index), None) => {
+                self.remove_volatile_slot(index);
+                None
+            }
+            (None, Some(_)) => {
+                self.volatile.push(key.clone());
+                Some(self.volatile.len() - 1)
+            }
+            (None, None) => None,
+        };
+        let old = Arc::make_mut(&mut self.map).insert(key.clone(), value);
+        if let Some(old) = &old {
+            self.forget(entry_size(&key, old));
+        }
+        old
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        if !self.map.contains_key(key) {
+            return None;
+        }
+        let value = Arc::make_mut(&mut self.map).remove(key)?;
+        self.remove_slot(value.slot);
+        if let Some(index) = value.volatile_slot {
+            self.remove_volatile_slot(index);
+        }
+        self.forget(entry_size(key, &value));
+        Some(value)
+    }
+
+    // 末尾のキーを空いた位置に移す
+    fn remove_slot(&mut self, index: usize) {
+        self.slots.swap_remove(index);
+        if let Some(moved) = self.slots.get(index) {
+            Arc::make_mut(&mut self.map).get_mut(moved).unwrap().slot = index;
+        }
+    }
+
+    fn remove_volatile_slot(&mut self, index: usize) {
+        self.volatile.swap_remove(index);
+        if let Some(moved) = self.volatile.get(index) {
+            Arc::make_mut(&mut self.map)
+                .get_mut(moved)
+                .unwrap()
+                .volatile_slot = Some(index);
+        }
+    }
+
+    fn forget(&mut self, size: usize) {
+        self.used_memory -= size;
+        USED_MEMORY.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.map.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        if !self.map.contains_key(key) {
+            return None;
+        }
+        Arc::make_mut(&mut self.map).get_mut(key)
+    }
+
+    // 残ったキーの並びの順序は保つ。消すキーがなければ map を複製しない
+    fn retain(&mut self, mut f: impl FnMut(&String, &Value) -> bool) {
+        if self.map.iter().all(|(key, value)| f(key, value)) {
+            return;
+        }
+        let mut freed = 0;
+        Arc::make_mut(&mut self.map).retain(|key, value| {
+            let keep = f(key, value);
+            if !keep {
+                freed += entry_size(key, value);
+            }
+            keep
+        });
+        self.forget(freed);
+        let map = Arc::make_mut(&mut self.map);
+        self.slots.retain(|key| map.contains_key(key));
+        for (index, key) in self.slots.iter().enumerate() {
+            map.get_mut(key).unwrap().slot = index;
+        }
+        self.volatile.retain(|key| map.contains_key(key));
+        for (index, key) in self.volatile.iter().enumerate() {
+            map.get_mut(key).unwrap().volatile_slot = Some(index);
+        }
+    }
+
+    // 中身を取り出して空にする。取り出した値はメモリ使用量に数えない
+    fn take(&mut self) -> Arc<HashMap<String, Value>> {
+        let used_memory = self.used_memory;
+        self.forget(used_memory);
+        self.slots.clear();
+        self.volatile.clear();
+        std::mem::take(&mut self.map)
+    }
+
+    // 無作為なキーを1つ返す。volatile_only なら有効期限付きのキーから選ぶ
+    fn random_entry(&self, volatile_only: bool) -> Option<(&String, &Value)> {
+        let keys = if volatile_only {
+            &self.volatile
+        } else {
+            &self.slots
+        };
+        if keys.is_empty() {
+            return None;
+        }
+        self.map
+            .get_key_value(&keys[random::random_index(keys.len())])
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.map.iter()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Value> {
+        self.map.values()
+    }
+}
+
+fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.value.size() + ENTRY_OVERHEAD
+}
+
+struct Database {
+    shards: Vec<Mutex<Db>>,
+}
+
+impl Database {
+    fn new() -> Self {
+        Database {
+            shards: (0..SHARDS).map(|_| Mutex::new(Db::default())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, Db> {
+        self.shards[shard_index(key)].lock().unwrap()
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<'_, Db>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap())
+            .collect()
+    }
+}
+
+// 複数のキーにまたがるコマンド用。必要なシャードを決まった順序でまとめてロックする
+struct ShardLocks<'a> {
+    guards: Vec<((usize, usize), MutexGuard<'a, Db>)>,
+}
+
+impl<'a> ShardLocks<'a> {
+    fn lock(databases: &'a [Database], keys: &[(usize, &str)]) -> Self {
+        let mut ids = keys
+            .iter()
+            .map(|(db, key)| (*db, shard_index(key)))
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        let guards = ids
+            .into_iter()
+            .map(|(db, shard)| ((db, shard), databases[db].shards[shard].lock().unwrap()))
+            .collect();
+        ShardLocks { guards }
+    }
+
+    fn get(&mut self, db: usize, key: &str) -> &mut Db {
+        let id = (db, shard_index(key));
+        let (_, guard) = self.guards.iter_mut().find(|(i, _)| *i == id).unwrap();
+        guard
+    }
 }
 
 #[derive(Clone)]
 struct Value {
-    value: String,
+    value: Arc<Data>,
     expires_at: Option<u128>,
+    // LRU 用の最終アクセス時刻 (ms) と LFU 用の対数カウンタ
+    last_access: u128,
+    lfu_counter: u8,
+    // Db の並びの中の位置。Db::insert が設定する
+    slot: usize,
+    volatile_slot: Option<usize>,
+}
+
+impl Value {
+    fn new(value: Data, expires_at: Option<u128>) -> Self {
+        Value {
+            value: Arc::new(value),
+            expires_at,
+            last_access: now(),
+            lfu_counter: LFU_INIT_VAL,
+            slot: 0,
+            volatile_slot: None,
+        }
+    }
+
+    // Redis と同じく、アクセスがない間は LFU_DECAY_MINUTES ごとにカウンタを1減らし、
+    // アクセスのたびにカウンタが大きいほど低い確率で1増やす
+    fn touch(&mut self) {
+        let counter = self.lfu_decayed();
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        let r = random::random_u64() as f64 / u64::MAX as f64;
+        self.lfu_counter = if counter < u8::MAX && r < p {
+            counter + 1
+        } else {
+            counter
+        };
+        self.last_access = now();
+    }
+
+    fn lfu_decayed(&self) -> u8 {
+        let periods = self.idle_time() / 60_000 / LFU_DECAY_MINUTES;
+        self.lfu_counter
+            .saturating_sub(periods.min(u8::MAX as u128) as u8)
+    }
+
+    fn idle_time(&self) -> u128 {
+        now().saturating_sub(self.last_access)
+    }
+
+    fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at < now(),
+            None => false,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.value.type_name()
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum StoreError {
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("ERR DB index is out of range")]
+    DbIndexOutOfRange,
+    #[error("ERR source and destination objects are the same")]
+    SameObject,
+    #[error("OOM command not allowed when used memory > 'maxmemory'.")]
+    OutOfMemory,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+}
+
+// RESTORE の IDLETIME/FREQ で引き継ぐ LRU/LFU のアクセス情報
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAccess {
+    // 最後のアクセスからの経過時間 (ms)
+    IdleTime(u128),
+    Freq(u8),
+}
+
+fn new_databases(databases: usize) -> Vec<Database> {
+    (0..databases).map(|_| Database::new()).collect()
+}
+
+// 起動時に一度だけ呼ぶ。既存のデータは破棄される
+pub fn init(databases: usize) {
+    *STORE.write().unwrap() = new_databases(databases);
+}
+
+pub fn databases() -> usize {
+    STORE.read().unwrap().len()
+}
+
+pub fn check_db(db: usize) -> Result<(), StoreError> {
+    if db < databases() {
+        Ok(())
+    } else {
+        Err(StoreError::DbIndexOutOfRange)
+    }
+}
+
+pub fn set(db: usize, key: &str, value: &str, px: Option<u128>) {
+    set_with_expires_at(db, key, value, px.map(|px| now() + px));
+}
+
+// 有効期限を UNIX 時刻 (ms) で指定する。RDB の読み込みなどで使う
+pub fn set_with_expires_at(db: usize, key: &str, value: &str, expires_at: Option<u128>) {
+    set_data(db, key, Data::String(value.to_string()), expires_at);
+}
+
+// 文字列以外も含めて値をそのまま置く。RDB の読み込みなどで使う
+pub fn set_data(db: usize, key: &str, data: Data, expires_at: Option<u128>) {
+    let value = Value::new(data, expires_at);
+    let databases = STORE.read().unwrap();
+    databases[db].shard(key).insert(key.to_string(), value);
+}
+
+// 型を問わず値と有効期限を返す。DUMP などで使う
+pub fn get_data(db: usize, key: &str) -> Option<(Data, Option<u128>)> {
+    let databases = STORE.read().unwrap();
+    let mut shard = databases[db].shard(key);
+    get_live(&mut shard, key).map(|value| ((*value.value).clone(), value.expires_at))
+}
+
+// RESTORE: 既存の値は置き換える。有効期限が過ぎていれば、既存の値を消すだけで追加しない
+pub fn restore(
+    db: usize,
+    key: &str,
+    data: Data,
+    expires_at: Option<u128>,
+    access: Option<KeyAccess>,
+) {
+    let mut value = Value::new(data, expires_at);
+    match access {
+        Some(KeyAccess::IdleTime(idle)) => value.last_access = now().saturating_sub(idle),
+        Some(KeyAccess::Freq(freq)) => value.lfu_counter = freq,
+        None => {}
+    }
+    let databases = STORE.read().unwrap();
+    let mut shard = databases[db].shard(key);
+    shard.remove(key);
+    if !value.is_expired() {
+        shard.insert(key.to_string(), value);
+    }
+}
+
+pub fn get(db: usize, key: &str) -> Result<Option<String>, StoreError> {
+    let databases = STORE.read().unwrap();
+    let mut shard = databases[db].shard(key);
+    match get_live(&mut shard, key).map(|value| &*value.value) {
+        Some(Data::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(StoreError::WrongType),
+        None => Ok(None),
+    }
+}
+
+// 期限切れのキーはここで削除して、存在しないものとして扱う。
+// 読み出したキーは LRU/LFU のアクセス情報を更新する
+fn get_live<'a>(store: &'a mut Db, key: &str) -> Option<&'a Value> {
+    if store.get(key).is_some_and(Value::is_expired) {
+        store.remove(key);
+    }
+    let value = store.get_mut(key)?;
+    value.touch();
+    Some(value)
+}
+
+fn remove_live(store: &mut Db, key: &str) -> Option<Value> {
+    let value = store.remove(key)?;
+    if value.is_expired() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn db_keys(db: usize, keys: &[String]) -> Vec<(usize, &str)> {
+    keys.iter().map(|key| (db, key.as_str())).collect()
+}
+
+pub fn del(db: usize, keys: &[String]) -> usize {
+    let databases = STORE.read().unwrap();
+    let mut locks = ShardLocks::lock(&databases, &db_keys(db, keys));
+    keys.iter()
+        .filter(|key| remove_live(locks.get(db, key), key).is_some())
+        .count()
+}
+
+// キーの削除だけをロック内で行い、値の解放はバックグラウンドスレッドに任せる
+pub fn unlink(db: usize, keys: &[String]) -> usize {
+    let removed = {
+        let databases = STORE.read().unwrap();
+        let mut locks = ShardLocks::lock(&databases, &db_keys(db, keys));
+        keys.iter()
+            .filter_map(|key| remove_live(locks.get(db, key), key))
+            .collect::<Vec<_>>()
+    };
+    let count = removed.len();
+    if count > 0 {
+        lazy_free(removed);
+    }
+    count
+}
+
+fn lazy_free<T: Send + 'static>(value: T) {
+    thread::spawn(move || drop(value));
 }
 
-pub fn set(key: &str, value: &str, px: Option<u128>) {
-    let expires_at = match px {
-        Some(px) => Some(now() + px),
-        None => None,
+// 同じキーが複数回指定された場合はその回数分数える
+pub fn exists(db: usize, keys: &[String]) -> usize {
+    let databases = STORE.read().unwrap();
+    let mut locks = ShardLocks::lock(&databases, &db_keys(db, keys));
+    keys.iter()
+        .filter(|key| get_live(locks.get(db, key), key).is_some())
+        .count()
+}
+
+pub fn key_type(db: usize, key: &str) -> Option<&'static str> {
+    let databases = STORE.read().unwrap();
+    let mut shard = databases[db].shard(key);
+    get_live(&mut shard, key).map(Value::type_name)
+}
+
+// TTL は移動先のキーにそのまま引き継ぐ
+pub fn rename(db: usize, src: &str, dst: &str) -> Result<(), StoreError> {
+    let databases = STORE.read().unwrap();
+    let mut locks = ShardLocks::lock(&databases, &[(db, src), (db, dst)]);
+    let value = remove_live(locks.get(db, src), src).ok_or(StoreError::NoSuchKey)?;
+    locks.get(db, dst).insert(dst.to_string(), value);
+    Ok(())
+}
+
+pub fn renamenx(db: usize, src: &str, dst: &str) -> Result<bool, StoreError> {
+    let databases = STORE.read().unwrap();
+    let mut locks = ShardLocks::lock(&databases, &[(db, src), (db, dst)]);
+    if get_live(locks.get(db, src), src).is_none() {
+        return Err(StoreError::NoSuchKey);
+    }
+    if get_live(locks.get(db, dst), dst).is_some() {
+        return Ok(false);
+    }
+    let value = locks.get(db, src).remove(src).unwrap();
+    locks.get(db, dst).insert(dst.to_string(), value);
+    Ok(true)
+}
+
+pub fn copy(
+    src_db: usize,
+    src: &str,
+    dst_db: usize,
+    dst: &str,
+    replace: bool,
+) -> Result<bool, StoreError> {
+    let databases = STORE.read().unwrap();
+    if dst_db >= databases.len() {
+        return Err(StoreError::DbIndexOutOfRange);
+    }
+    if src_db == dst_db && src == dst {
+        return Err(StoreError::SameObject);
+    }
+    let mut locks = ShardLocks::lock(&databases, &[(src_db, src), (dst_db, dst)]);
+    let value = match get_live(locks.get(src_db, src), src) {
+        Some(value) => value.clone(),
+        None => return Ok(false),
+    };
+    if !replace && get_live(locks.get(dst_db, dst), dst).is_some() {
+        return Ok(false);
+    }
+    locks.get(dst_db, dst).insert(dst.to_string(), value);
+    Ok(true)
+}
+
+// 移動先に同じキーがある場合は何もしない
+pub fn move_key(src_db: usize, key: &str, dst_db: usize) -> Result<bool, StoreError> {
+    let databases = STORE.read().unwrap();
+    if dst_db >= databases.len() {
+        return Err(StoreError::DbIndexOutOfRange);
+    }
+    if src_db == dst_db {
+        return Err(StoreError::SameObject);
+    }
+    let mut locks = ShardLocks::lock(&databases, &[(src_db, key), (dst_db, key)]);
+    if get_live(locks.get(src_db, key), key).is_none()
+        || get_live(locks.get(dst_db, key), key).is_some()
+    {
+        return Ok(false);
+    }
+    let value = locks.get(src_db, key).remove(key).unwrap();
+    locks.get(dst_db, key).insert(key.to_string(), value);
+    Ok(true)
+}
+
+pub fn swapdb(db1: usize, db2: usize) -> Result<(), StoreError> {
+    let mut databases = STORE.write().unwrap();
+    if db1 >= databases.len() || db2 >= databases.len() {
+        return Err(StoreError::DbIndexOutOfRange);
+    }
+    databases.swap(db1, db2);
+    Ok(())
+}
+
+// async の場合は空の HashMap と差し替えて、古い方の解放をバックグラウンドで行う
+pub fn flushdb(db: usize, async_free: bool) {
+    let old = {
+        let databases = STORE.read().unwrap();
+        let mut shards = databases[db].lock_all();
+        shards
+            .iter_mut()
+            .map(|shard| shard.take())
+            .collect::<Vec<_>>()
     };
-    let value = Value {
-        value: value.to_string(),
-        expires_at,
+    if async_free {
+        lazy_free(old);
+    }
+}
+
+pub fn flushall(async_free: bool) {
+    let old = {
+        let databases = STORE.write().unwrap();
+        databases
+            .iter()
+            .flat_map(|database| database.lock_all())
+            .map(|mut shard| shard.take())
+            .collect::<Vec<_>>()
     };
-    STORE.lock().unwrap().insert(key.to_string(), value);
+    if async_free {
+        lazy_free(old);
+    }
+}
+
+// 全 DB の内容を entries で置き換える。レプリカが全体の同期で受け取ったデータを反映するのに使う。
+// 別に作った DB に入れてから差し替えるので、入れている途中の状態がクライアントから見えることはない
+pub fn replace_all(entries: impl IntoIterator<Item = (usize, String, Data, Option<u128>)>) {
+    let staged = new_databases(databases());
+    for (db, key, data, expires_at) in entries {
+        staged[db]
+            .shard(&key)
+            .insert(key, Value::new(data, expires_at));
+    }
+    let old = std::mem::replace(&mut *STORE.write().unwrap(), staged);
+    let old = old
+        .iter()
+        .flat_map(|database| database.lock_all())
+        .map(|mut shard| shard.take())
+        .collect::<Vec<_>>();
+    lazy_free(old);
+}
+
+// INFO keyspace 用。キーが存在する DB について (db, keys, expires) を返す
+pub fn keyspace() -> Vec<(usize, usize, usize)> {
+    let databases = STORE.read().unwrap();
+    databases
+        .iter()
+        .enumerate()
+        .filter_map(|(db, database)| {
+            let mut keys = 0;
+            let mut expires = 0;
+            for mut shard in database.lock_all() {
+                shard.retain(|_, value| !value.is_expired());
+                keys += shard.len();
+                expires += shard.values().filter(|v| v.expires_at.is_some()).count();
+            }
+            if keys == 0 {
+                return None;
+            }
+            Some((db, keys, expires))
+        })
+        .collect()
+}
+
+// (key, value, expires_at)。値はストアと共有する
+pub type SnapshotEntry = (String, Arc<Data>, Option<u128>);
+
+// ある一時点の全 DB の内容。各シャードの map を共有するだけなので、取るのはシャード数に比例する時間で済む。
+// 以降の書き込みはそのシャードの map を複製してから行うので、スナップショットの内容は変わらない
+pub struct Snapshot {
+    databases: Vec<Vec<Arc<HashMap<String, Value>>>>,
+    taken_at: u128,
+}
+
+impl Snapshot {
+    // RDB に書き出すための、期限切れでないキー。バックグラウンドで呼べば、その間もクライアントは止まらない
+    pub fn entries(&self) -> Vec<(usize, Vec<SnapshotEntry>)> {
+        self.databases
+            .iter()
+            .enumerate()
+            .map(|(db, shards)| {
+                let entries = shards
+                    .iter()
+                    .flat_map(|shard| shard.iter())
+                    .filter(|(_, value)| value.expires_at.is_none_or(|e| e >= self.taken_at))
+                    .map(|(key, value)| (key.clone(), value.value.clone(), value.expires_at))
+                    .collect::<Vec<_>>();
+                (db, entries)
+            })
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect()
+    }
+}
+
+// 全シャードを同時にロックして、ある一時点の内容を取り出す
+pub fn snapshot() -> Snapshot {
+    let databases = STORE.read().unwrap();
+    let databases = databases
+        .iter()
+        .map(|database| {
+            database
+                .lock_all()
+                .iter()
+                .map(|shard| shard.map.clone())
+                .collect()
+        })
+        .collect();
+    Snapshot {
+        databases,
+        taken_at: now(),
+    }
+}
+
+pub fn touch(db: usize, keys: &[String]) -> usize {
+    exists(db, keys)
+}
+
+pub fn configure_eviction(config: EvictionConfig) {
+    *EVICTION.write().unwrap() = config;
+}
+
+pub fn eviction_config() -> EvictionConfig {
+    *EVICTION.read().unwrap()
+}
+
+pub fn used_memory() -> usize {
+    USED_MEMORY.load(Ordering::Relaxed)
+}
+
+pub fn evicted_keys() -> u64 {
+    EVICTED_KEYS.load(Ordering::Relaxed)
+}
+
+pub fn over_maxmemory() -> bool {
+    let config = eviction_config();
+    config.maxmemory != 0 && used_memory() > config.maxmemory
+}
+
+// maxmemory を超えている間、ポリシーに従ってキーを追い出す。追い出したキーごとに on_evict を呼ぶ。
+// 追い出せるキーがなければ OOM を返すので、呼び出し側はメモリを増やすコマンドを拒否する
+pub fn evict_if_needed(mut on_evict: impl FnMut(usize, &str)) -> Result<(), StoreError> {
+    let config = eviction_config();
+    while over_maxmemory() {
+        if config.policy == MaxmemoryPolicy::NoEviction {
+            return Err(StoreError::OutOfMemory);
+        }
+        let (db, key) = sample_eviction_candidate(&config).ok_or(StoreError::OutOfMemory)?;
+        // ロックを放してから on_evict を呼ぶ
+        let removed = STORE.read().unwrap()[db].shard(&key).remove(&key);
+        if removed.is_some() {
+            EVICTED_KEYS.fetch_add(1, Ordering::Relaxed);
+            on_evict(db, &key);
+        }
+    }
+    Ok(())
+}
+
+// Redis と同様の近似アルゴリズム。各 DB から samples 個のキーを無作為に選び、
+// その中でポリシー上もっとも追い出すべきキーを返す
+fn sample_eviction_candidate(config: &EvictionConfig) -> Option<(usize, String)> {
+    let databases = STORE.read().unwrap();
+    let mut best: Option<(u128, usize, String)> = None;
+    for (db, database) in databases.iter().enumerate() {
+        for _ in 0..config.samples {
+            let Some((score, key)) = sample_key(database, config.policy) else {
+                // この DB には追い出せるキーがない
+                break;
+            };
+            if best.as_ref().is_none_or(|(best, _, _)| score > *best) {
+                best = Some((score, db, key));
+            }
+        }
+    }
+    best.map(|(_, db, key)| (db, key))
+}
+
+// 無作為なシャードから候補を1つ選び、追い出しの優先度 (大きいほど先に追い出す) と共に返す。
+// 選んだシャードに候補がなければ次のシャードを見る
+fn sample_key(database: &Database, policy: MaxmemoryPolicy) -> Option<(u128, String)> {
+    let start = random::random_index(SHARDS);
+    for i in 0..SHARDS {
+        let shard = database.shards[(start + i) % SHARDS].lock().unwrap();
+        let Some((key, value)) = shard.random_entry(policy.volatile_only()) else {
+            continue;
+        };
+        let score = if value.is_expired() {
+            u128::MAX
+        } else {
+            match policy {
+                MaxmemoryPolicy::AllkeysLru | MaxmemoryPolicy::VolatileLru => value.idle_time(),
+                MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                    (u8::MAX - value.lfu_decayed()) as u128
+                }
+                MaxmemoryPolicy::VolatileTtl => u128::MAX - 1 - value.expires_at.unwrap(),
+                MaxmemoryPolicy::AllkeysRandom
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::NoEviction => random::random_u64() as u128,
+            }
+        };
+        return Some((score, key.clone()));
+    }
+    None
 }
 
-pub fn get(key: &str) -> Option<String> {
-    match STORE.lock().unwrap().get(key) {
-        Some(value) => {
-            if let Some(expires_at) = value.expires_at {
-                if expires_at < now() {
-                    return None;
+pub fn random_key(db: usize) -> Option<String> {
+    let databases = STORE.read().unwrap();
+    let mut shards = databases[db].lock_all();
+    loop {
+        let total = shards.iter().map(|shard| shard.len()).sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+        let mut index = random::random_index(total);
+        let shard = shards
+            .iter_mut()
+            .find(|shard| {
+                if index < shard.len() {
+                    true
+                } else {
+                    index -= shard.len();
+                    false
                 }
+            })
+            .unwrap();
+        let key = shard.slots[index].clone();
+        if get_live(shard, &key).is_some() {
+            return Some(key);
+        }
+    }
+}
+
+pub fn dbsize(db: usize) -> usize {
+    let databases = STORE.read().unwrap();
+    let mut shards = databases[db].lock_all();
+    shards
+        .iter_mut()
+        .map(|shard| {
+            shard.retain(|_, value| !value.is_expired());
+            shard.len()
+        })
+        .sum()
+}
+
+pub fn keys(db: usize, pattern: &str) -> Vec<String> {
+    let databases = STORE.read().unwrap();
+    let shards = databases[db].lock_all();
+    shards
+        .iter()
+        .flat_map(|shard| shard.iter())
+        .filter(|(key, value)| !value.is_expired() && glob_match(pattern, key))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+// SCAN のカーソルは (シャード番号 << 32) | (次に見る位置 + 1)。下位 32 ビットが 0 ならシャードの末尾から始める。
+// 各シャードのキーの並びを末尾から先頭へ count 個ずつたどるので、1回の呼び出しで見るのは count 個程度。
+// 削除では末尾のキーが空いた位置に移るだけなので、まだ見ていないキーが見終えた位置に移ることはなく、
+// 反復中ずっと存在するキーは必ず一度は返される
+pub fn scan(
+    db: usize,
+    cursor: u64,
+    count: usize,
+    pattern: Option<&str>,
+    type_name: Option<&str>,
+) -> (u64, Vec<String>) {
+    let databases = STORE.read().unwrap();
+    let mut shard_index = (cursor >> 32) as usize;
+    let mut position = (cursor & 0xffff_ffff) as usize;
+    let mut keys = vec![];
+    let mut visited = 0;
+    while shard_index < SHARDS && visited < count.max(1) {
+        let shard = databases[db].shards[shard_index].lock().unwrap();
+        // キーが減っていれば、残っている末尾から続ける
+        let mut next = match position {
+            0 => shard.slots.len(),
+            position => position.min(shard.slots.len()),
+        };
+        while next > 0 && visited < count.max(1) {
+            next -= 1;
+            visited += 1;
+            let key = &shard.slots[next];
+            let value = shard.get(key).unwrap();
+            if !value.is_expired()
+                && pattern.is_none_or(|pattern| glob_match(pattern, key))
+                && type_name.is_none_or(|t| value.type_name() == t)
+            {
+                keys.push(key.clone());
             }
-            Some(value.value.clone())
         }
-        None => None,
+        if next > 0 {
+            return (((shard_index as u64) << 32) | next as u64, keys);
+        }
+        shard_index += 1;
+        position = 0;
+    }
+    if shard_index >= SHARDS {
+        return (0, keys);
     }
+    ((shard_index as u64) << 32, keys)
 }
 
+// シャードの振り分けに使う、プロセスをまたいでも変わらないハッシュ値
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn shard_index(key: &str) -> usize {
+    (key_hash(key) % SHARDS as u64) as usize
+}
+
+// 現在の UNIX 時刻 (ms)
 // TODO: テスタブルな形にする
-fn now() -> u128 {
+pub fn now() -> u128 {
     time::SystemTime::now()
         .duration_since(time::UNIX_EPOCH)
         .unwrap()
@@ -51,19 +944,305 @@ fn now() -> u128 {
 mod tests {
     use super::*;
 
+    fn strings(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
     #[test]
     fn test_set_get() {
-        set("key1", "value1", None);
-        assert_eq!(get("key1"), Some("value1".to_string()));
+        set(0, "key1", "value1", None);
+        assert_eq!(get(0, "key1"), Ok(Some("value1".to_string())));
+    }
+
+    #[test]
+    fn test_get_wrong_type() {
+        let list = Data::List(["a".to_string()].into_iter().collect());
+        set_data(0, "wrongtype1", list, None);
+        assert_eq!(get(0, "wrongtype1"), Err(StoreError::WrongType));
+        assert_eq!(key_type(0, "wrongtype1"), Some("list"));
     }
 
     #[test]
     fn test_set_get_expired() {
-        set("key2", "value2", Some(1000000000));
-        assert_eq!(get("key2"), Some("value2".to_string()));
-        set("key3", "value3", Some(0));
+        set(0, "key2", "value2", Some(1000000000));
+        assert_eq!(get(0, "key2"), Ok(Some("value2".to_string())));
+        set(0, "key3", "value3", Some(0));
         // sleep
         std::thread::sleep(std::time::Duration::from_secs(1));
-        assert_eq!(get("key3"), None);
+        assert_eq!(get(0, "key3"), Ok(None));
+    }
+
+    #[test]
+    fn test_del_exists() {
+        set(0, "del1", "v", None);
+        set(0, "del2", "v", None);
+        assert_eq!(exists(0, &strings(&["del1", "del1", "del2", "del3"])), 3);
+        assert_eq!(del(0, &strings(&["del1", "del2", "del3"])), 2);
+        assert_eq!(exists(0, &strings(&["del1", "del2"])), 0);
+        set(0, "unlink1", "v", None);
+        assert_eq!(unlink(0, &strings(&["unlink1", "unlink2"])), 1);
+        assert_eq!(get(0, "unlink1"), Ok(None));
+    }
+
+    #[test]
+    fn test_rename_copy() {
+        set(0, "rename1", "v1", Some(1000000000));
+        assert_eq!(rename(0, "rename1", "rename2"), Ok(()));
+        assert_eq!(get(0, "rename1"), Ok(None));
+        assert_eq!(get(0, "rename2"), Ok(Some("v1".to_string())));
+        assert!(STORE.read().unwrap()[0]
+            .shard("rename2")
+            .get("rename2")
+            .unwrap()
+            .expires_at
+            .is_some());
+        assert_eq!(rename(0, "rename1", "rename2"), Err(StoreError::NoSuchKey));
+
+        set(0, "rename3", "v3", None);
+        assert_eq!(renamenx(0, "rename2", "rename3"), Ok(false));
+        assert_eq!(renamenx(0, "rename2", "rename4"), Ok(true));
+
+        assert_eq!(copy(0, "rename4", 0, "rename3", false), Ok(false));
+        assert_eq!(copy(0, "rename4", 0, "rename3", true), Ok(true));
+        assert_eq!(
+            copy(0, "rename4", 0, "rename4", true),
+            Err(StoreError::SameObject)
+        );
+        assert_eq!(get(0, "rename3"), Ok(Some("v1".to_string())));
+        assert_eq!(key_type(0, "rename3"), Some("string"));
+        assert_eq!(key_type(0, "rename5"), None);
+    }
+
+    #[test]
+    fn test_keys_scan() {
+        for i in 0..50 {
+            set(0, &format!("scan:{}", i), "v", None);
+        }
+        let mut found = keys(0, "scan:*");
+        found.sort();
+        assert_eq!(found.len(), 50);
+
+        let mut scanned = vec![];
+        let mut cursor = 0;
+        for i in 0.. {
+            let (next, keys) = scan(0, cursor, 7, Some("scan:*"), Some("string"));
+            scanned.extend(keys);
+            // 反復中にキーを増やしたり消したりしても、既存のキーは漏れない
+            set(0, &format!("scan-extra:{}", i), "v", None);
+            if i % 2 == 1 {
+                del(0, &[format!("scan-extra:{}", i - 1)]);
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        scanned.sort();
+        scanned.dedup();
+        assert_eq!(scanned, found);
+    }
+
+    #[test]
+    fn test_move_swapdb() {
+        set(1, "move1", "v1", None);
+        assert_eq!(move_key(1, "move1", 2), Ok(true));
+        assert_eq!(get(1, "move1"), Ok(None));
+        assert_eq!(get(2, "move1"), Ok(Some("v1".to_string())));
+        set(1, "move1", "v2", None);
+        assert_eq!(move_key(1, "move1", 2), Ok(false));
+        assert_eq!(move_key(1, "move1", 1), Err(StoreError::SameObject));
+        assert_eq!(
+            move_key(1, "move1", 1000),
+            Err(StoreError::DbIndexOutOfRange)
+        );
+
+        assert_eq!(copy(2, "move1", 3, "copy1", false), Ok(true));
+        assert_eq!(get(3, "copy1"), Ok(Some("v1".to_string())));
+
+        assert_eq!(swapdb(1, 2), Ok(()));
+        assert_eq!(get(1, "move1"), Ok(Some("v1".to_string())));
+        assert_eq!(get(2, "move1"), Ok(Some("v2".to_string())));
+
+        flushdb(3, true);
+        assert_eq!(dbsize(3), 0);
+    }
+
+    #[test]
+    fn test_snapshot_copy_on_write() {
+        set(6, "cow1", "v1", None);
+        set(6, "cow2", "v2", None);
+        let snapshot = snapshot();
+        // スナップショットを取った後の書き込みは、スナップショットに影響しない
+        set(6, "cow1", "changed", None);
+        del(6, &strings(&["cow2"]));
+        set(6, "cow3", "v3", None);
+        let (_, mut entries) = snapshot
+            .entries()
+            .into_iter()
+            .find(|(db, _)| *db == 6)
+            .unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "cow1".to_string(),
+                    Arc::new(Data::String("v1".to_string())),
+                    None
+                ),
+                (
+                    "cow2".to_string(),
+                    Arc::new(Data::String("v2".to_string())),
+                    None
+                ),
+            ]
+        );
+        assert_eq!(get(6, "cow1"), Ok(Some("changed".to_string())));
+        flushdb(6, false);
+    }
+
+    #[test]
+    fn test_snapshot_shares_values() {
+        // "share" と同じシャードに入る別のキー
+        let other = (0..)
+            .map(|i| format!("share:{}", i))
+            .find(|key| shard_index(key) == shard_index("share"))
+            .unwrap();
+        set_data(7, "share", Data::List(strings(&["a", "b"]).into()), None);
+        let snapshot = snapshot();
+        // スナップショットを持ったまま同じシャードに書き込むと map は複製されるが、
+        // 書き込んでいないキーの値は複製されずにスナップショットと共有したまま
+        set(7, &other, "v", None);
+        {
+            let databases = STORE.read().unwrap();
+            let shard = databases[7].shard("share");
+            let shared = &snapshot.databases[7][shard_index("share")];
+            assert!(!Arc::ptr_eq(&shard.map, shared));
+            assert!(Arc::ptr_eq(
+                &shard.get("share").unwrap().value,
+                &shared["share"].value
+            ));
+        }
+        flushdb(7, false);
+    }
+
+    #[test]
+    fn test_multi_key_no_deadlock() {
+        // 逆順でキーを指定する複数キーコマンドを並行に走らせてもデッドロックしない
+        let keys = (0..32)
+            .map(|i| format!("deadlock:{}", i))
+            .collect::<Vec<_>>();
+        for key in &keys {
+            set(0, key, "v", None);
+        }
+        let handles = (0..4)
+            .map(|t| {
+                let mut keys = keys.clone();
+                if t % 2 == 1 {
+                    keys.reverse();
+                }
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        for pair in keys.windows(2) {
+                            let _ = copy(0, &pair[0], 0, &pair[1], true);
+                        }
+                        exists(0, &keys);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(exists(0, &keys), keys.len());
+    }
+
+    #[test]
+    fn test_db_memory_accounting() {
+        let mut db = Db::default();
+        db.insert(
+            "mem1".to_string(),
+            Value::new(Data::String("abc".to_string()), None),
+        );
+        db.insert(
+            "mem2".to_string(),
+            Value::new(Data::String("abcdef".to_string()), None),
+        );
+        assert_eq!(db.used_memory, 4 + 3 + 4 + 6 + ENTRY_OVERHEAD * 2);
+        db.insert(
+            "mem1".to_string(),
+            Value::new(Data::String("a".to_string()), None),
+        );
+        assert_eq!(db.used_memory, 4 + 1 + 4 + 6 + ENTRY_OVERHEAD * 2);
+        db.remove("mem2");
+        assert_eq!(db.used_memory, 4 + 1 + ENTRY_OVERHEAD);
+        db.take();
+        assert_eq!(db.used_memory, 0);
+    }
+
+    #[test]
+    fn test_db_slots() {
+        let mut db = Db::default();
+        for key in ["slot1", "slot2", "slot3"] {
+            db.insert(
+                key.to_string(),
+                Value::new(Data::String("v".to_string()), Some(now() + 100000)),
+            );
+        }
+        // 上書きしても位置は変わらず、有効期限がなくなれば volatile から外れる
+        db.insert(
+            "slot2".to_string(),
+            Value::new(Data::String("v".to_string()), None),
+        );
+        assert_eq!(db.slots, strings(&["slot1", "slot2", "slot3"]));
+        assert_eq!(db.volatile, strings(&["slot1", "slot3"]));
+        // 削除した位置には末尾のキーが入る
+        db.remove("slot1");
+        assert_eq!(db.slots, strings(&["slot3", "slot2"]));
+        assert_eq!(db.volatile, strings(&["slot3"]));
+        db.retain(|key, _| key != "slot3");
+        assert_eq!(db.slots, strings(&["slot2"]));
+        assert!(db.volatile.is_empty());
+        for (index, key) in db.slots.iter().enumerate() {
+            assert_eq!(db.get(key).unwrap().slot, index);
+        }
+        assert_eq!(db.random_entry(false).unwrap().0, "slot2");
+        assert!(db.random_entry(true).is_none());
+    }
+
+    #[test]
+    fn test_sample_key() {
+        let database = Database::new();
+        database.shard("persistent").insert(
+            "persistent".to_string(),
+            Value::new(Data::String("v".to_string()), None),
+        );
+        assert_eq!(sample_key(&database, MaxmemoryPolicy::VolatileLru), None);
+        let expires_at = now() + 100000;
+        database.shard("volatile").insert(
+            "volatile".to_string(),
+            Value::new(Data::String("v".to_string()), Some(expires_at)),
+        );
+        for _ in 0..20 {
+            let (score, key) = sample_key(&database, MaxmemoryPolicy::VolatileTtl).unwrap();
+            assert_eq!(key, "volatile");
+            assert_eq!(score, u128::MAX - 1 - expires_at);
+        }
+        database.lock_all().iter_mut().for_each(|shard| {
+            shard.take();
+        });
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        let mut value = Value::new(Data::String("v".to_string()), None);
+        for _ in 0..1000 {
+            value.touch();
+        }
+        assert!(value.lfu_counter > LFU_INIT_VAL);
+        let counter = value.lfu_counter;
+        // 3分間アクセスがなければカウンタは3減る
+        value.last_access -= 3 * 60_000;
+        assert_eq!(value.lfu_decayed(), counter - 3);
     }
 }
@@ -0,0 +1,104 @@
+// Redis 7 より前の RDB で小さなリスト・ハッシュ・ソート済みセットに使われていた ziplist。
+// ヘッダー (全体のバイト数 u32, 最後の要素の位置 u32, 要素数 u16) に続いて各要素が並び、0xff で終わる。
+// 各要素は直前の要素の長さ (prevlen)、エンコーディング、データからなる
+use crate::resp::bytes_to_string;
+
+const HEADER_SIZE: usize = 10;
+const EOF: u8 = 0xff;
+
+// 整数は10進表記の文字列として返す
+pub fn decode(input: &[u8]) -> Option<Vec<String>> {
+    if input.len() < HEADER_SIZE + 1 {
+        return None;
+    }
+    let total = u32::from_le_bytes(input[0..4].try_into().unwrap()) as usize;
+    if total != input.len() || input[total - 1] != EOF {
+        return None;
+    }
+    let mut ret = vec![];
+    let mut pos = HEADER_SIZE;
+    while input[pos] != EOF {
+        // prevlen は 254 未満なら1バイト、それ以上なら 0xfe に続く4バイト
+        pos += if input[pos] == 0xfe { 5 } else { 1 };
+        let (element, len) = decode_element(input.get(pos..total - 1)?)?;
+        ret.push(element);
+        pos += len;
+        if pos >= total {
+            return None;
+        }
+    }
+    // 要素数が 65535 以上のときは 65535 になっていて、数えないと分からない
+    let count = u16::from_le_bytes(input[8..10].try_into().unwrap());
+    if count != u16::MAX && count as usize != ret.len() {
+        return None;
+    }
+    Some(ret)
+}
+
+// 要素と、prevlen を除いた要素のバイト数を返す
+fn decode_element(input: &[u8]) -> Option<(String, usize)> {
+    let first = *input.first()?;
+    let int = |n: i64, len: usize| Some((n.to_string(), len));
+    let bytes = |start: usize, len: usize| {
+        let data = input.get(start..start + len)?;
+        Some((bytes_to_string(data), start + len))
+    };
+    match first {
+        // 00pppppp: 6ビットの長さの文字列
+        0x00..=0x3f => bytes(1, first as usize),
+        // 01pppppp qqqqqqqq: 14ビット (ビッグエンディアン) の長さの文字列
+        0x40..=0x7f => {
+            let len = (((first & 0x3f) as usize) << 8) | *input.get(1)? as usize;
+            bytes(2, len)
+        }
+        0x80 => {
+            let len = u32::from_be_bytes(input.get(1..5)?.try_into().unwrap());
+            bytes(5, len as usize)
+        }
+        0xc0 => int(
+            i16::from_le_bytes(input.get(1..3)?.try_into().unwrap()) as i64,
+            3,
+        ),
+        0xd0 => int(
+            i32::from_le_bytes(input.get(1..5)?.try_into().unwrap()) as i64,
+            5,
+        ),
+        0xe0 => int(i64::from_le_bytes(input.get(1..9)?.try_into().unwrap()), 9),
+        0xf0 => {
+            let b = input.get(1..4)?;
+            // 24ビットを上位に詰めてから算術シフトで符号を拡張する
+            let n = i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8;
+            int(n as i64, 4)
+        }
+        0xfe => int(*input.get(1)? as i8 as i64, 2),
+        // 1111xxxx: 0 から 12 までの整数 (xxxx - 1)
+        0xf1..=0xfd => int((first & 0x0f) as i64 - 1, 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // RPUSH l a 12 -300 hello の ziplist。12 は即値、-300 は int16 になる
+        let data = b"\x1b\x00\x00\x00\x13\x00\x00\x00\x04\x00\
+            \x00\x01a\
+            \x03\xfd\
+            \x02\xc0\xd4\xfe\
+            \x04\x05hello\
+            \xff";
+        assert_eq!(
+            decode(data),
+            Some(vec![
+                "a".to_string(),
+                "12".to_string(),
+                "-300".to_string(),
+                "hello".to_string()
+            ])
+        );
+        assert_eq!(decode(&data[..data.len() - 1]), None);
+    }
+}
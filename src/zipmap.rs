@@ -0,0 +1,63 @@
+// Redis 2.6 より前の RDB で小さなハッシュに使われていた zipmap。
+// 要素数 (1バイト) に続いて、長さ付きのキー、長さと空きバイト数付きの値が交互に並び、0xff で終わる
+use crate::resp::bytes_to_string;
+
+const EOF: u8 = 0xff;
+
+// キーと値を交互に並べて返す
+pub fn decode(input: &[u8]) -> Option<Vec<String>> {
+    let mut ret = vec![];
+    let mut pos = 1;
+    loop {
+        if *input.get(pos)? == EOF {
+            break;
+        }
+        let key_len = read_len(input, &mut pos)?;
+        ret.push(bytes_to_string(input.get(pos..pos + key_len)?));
+        pos += key_len;
+        let value_len = read_len(input, &mut pos)?;
+        let free = *input.get(pos)? as usize;
+        pos += 1;
+        ret.push(bytes_to_string(input.get(pos..pos + value_len)?));
+        pos += value_len + free;
+    }
+    (pos == input.len() - 1).then_some(ret)
+}
+
+// 長さは 254 未満なら1バイト、それ以上なら 0xfe に続く4バイト
+fn read_len(input: &[u8], pos: &mut usize) -> Option<usize> {
+    let first = *input.get(*pos)?;
+    match first {
+        0..=253 => {
+            *pos += 1;
+            Some(first as usize)
+        }
+        0xfe => {
+            let len = u32::from_le_bytes(input.get(*pos + 1..*pos + 5)?.try_into().unwrap());
+            *pos += 5;
+            Some(len as usize)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // HSET h a 1 name redis の zipmap。2つ目の値の後ろには空きが1バイトある
+        let data = b"\x02\x01a\x01\x001\x04name\x05\x01redis\x00\xff";
+        assert_eq!(
+            decode(data),
+            Some(vec![
+                "a".to_string(),
+                "1".to_string(),
+                "name".to_string(),
+                "redis".to_string()
+            ])
+        );
+        assert_eq!(decode(&data[..data.len() - 1]), None);
+    }
+}
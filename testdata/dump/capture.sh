@@ -0,0 +1,45 @@
+#!/bin/bash
+#
+# 起動中の redis-server で決まったコマンドを実行し、各キーの DUMP を
+# testdata/dump/redis-<redis_version>/<キー>.bin に保存する。
+# rdb.rs の test_restore_dump_fixtures がこれを読んで値を確かめる。
+#
+# 使い方: testdata/dump/capture.sh [-h host] [-p port]
+#
+# DB 15 の list hash zset intset set lzf stream を上書きして最後に消すので、
+# テスト用のサーバーに対して実行すること
+set -euo pipefail
+
+args=("$@")
+cli() {
+    redis-cli ${args[@]+"${args[@]}"} -n 15 "$@"
+}
+
+version=$(cli INFO server | tr -d '\r' | sed -n 's/^redis_version://p')
+dir=$(dirname "$0")/redis-$version
+mkdir -p "$dir"
+
+keys=(list hash zset intset set lzf stream)
+cli DEL "${keys[@]}" >/dev/null
+cli RPUSH list a 1 hello >/dev/null
+cli HMSET hash f v n 12 >/dev/null
+cli ZADD zset 1 a 2.5 b >/dev/null
+cli SADD intset 3 1 2 >/dev/null
+cli SADD set a b >/dev/null
+# rdbcompression (既定で yes) により LZF で圧縮される
+cli SET lzf "$(printf 'a%.0s' {1..100})" >/dev/null
+# ストリームは 5.0 から
+if [ "${version%%.*}" -ge 5 ]; then
+    cli XADD stream 1-1 f v >/dev/null
+    cli XGROUP CREATE stream g 0 >/dev/null
+    cli XREADGROUP GROUP g c STREAMS stream '>' >/dev/null
+fi
+
+for key in "${keys[@]}"; do
+    if [ "$(cli EXISTS "$key")" = 1 ]; then
+        # --raw は値の後ろに改行を1つ付ける
+        cli --raw DUMP "$key" | head -c -1 >"$dir/$key.bin"
+    fi
+done
+cli DEL "${keys[@]}" >/dev/null
+echo "saved to $dir"